@@ -0,0 +1,271 @@
+use crate::instructions::{InstructionMode, Instructions};
+use crate::utils::{disassemble, Parameter};
+use crate::verify::is_exit;
+
+// Control Flow Graph:
+//
+// A program decoded at its text address (see utils::decode_instructions_at) is split into basic
+// blocks - runs of instructions that are always executed start to finish. A block starts at the
+// entry of the program, at every jump or branch target, and after every JMP, IF*, CALL, RET, IRET,
+// HLT, SYS EXIT and invalid opcode. As in verify.rs, SYS only counts as EXIT when the instruction
+// before it is `SET R0, EXIT`.
+//
+// Edges:
+// - FallThrough: to the next instruction (not after JMP, RET, IRET, HLT, SYS EXIT or an invalid
+//   opcode). CALL falls through to where the function returns
+// - Branch: to the Immediate target of JMP, IF* or CALL
+//
+// Jumps to a register, RET and IRET can go anywhere, so their block is marked as dynamic
+// instead of having edges. Targets outside of the program, or between instructions, are left out.
+//
+// Blocks are identified by their index in `blocks`, which is in program order.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EdgeKind{
+    FallThrough,
+    Branch,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Edge{
+    pub target: usize, // Block index
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock{
+    pub start: usize, // Index of the first instruction
+    pub address: u32, // Code address of the first instruction
+    pub end: usize,   // Index after the last instruction
+    pub successors: Vec<Edge>,
+    pub dynamic: bool, // Ends in a jump that can't be followed statically
+}
+
+pub struct Cfg{
+    program: Vec<(Instructions, InstructionMode, Vec<Parameter>)>,
+    text_address: u32,
+    pub blocks: Vec<BasicBlock>,
+}
+
+// Does the instruction at this index end a block?
+fn is_terminator(program: &[(Instructions, InstructionMode, Vec<Parameter>)], index: usize) -> bool{
+    match program[index].0{
+        Instructions::JMP | Instructions::CALL | Instructions::RET | Instructions::IRET | Instructions::HLT | Instructions::UD => true,
+        Instructions::SYS => is_exit(program, index),
+        x => is_conditional(x),
+    }
+}
+
+// Does execution stop at the instruction at this index, or go somewhere other than the next one?
+fn falls_through(program: &[(Instructions, InstructionMode, Vec<Parameter>)], index: usize) -> bool{
+    match program[index].0{
+        Instructions::JMP | Instructions::RET | Instructions::IRET | Instructions::HLT | Instructions::UD => false,
+        Instructions::SYS => !is_exit(program, index),
+        _ => true,
+    }
+}
+
+// The instruction index a JMP, IF* or CALL goes to, if it's known and in the program
+fn branch_target(program: &[(Instructions, InstructionMode, Vec<Parameter>)], text_address: u32, index: usize) -> Option<usize>{
+    let (instruction, mode, args) = &program[index];
+    let branches = matches!(instruction, Instructions::JMP | Instructions::CALL) || is_conditional(*instruction);
+    if !branches || *mode != InstructionMode::Immediate{
+        return None;
+    }
+
+    let target = args.get(2)?.value.checked_sub(text_address)?;
+    if !target.is_multiple_of(4) || target as usize / 4 >= program.len(){
+        return None;
+    }
+
+    Some(target as usize / 4)
+}
+
+fn is_conditional(instruction: Instructions) -> bool{
+    matches!(instruction, Instructions::IF | Instructions::IFN | Instructions::IFG | Instructions::IFL | Instructions::IFE | Instructions::IFNE)
+}
+
+impl Cfg{
+    pub fn build(program: &[(Instructions, InstructionMode, Vec<Parameter>)], text_address: u32) -> Self{
+        // Find the first instruction of every block
+        let mut leaders = vec![false; program.len()];
+        if !program.is_empty(){
+            leaders[0] = true;
+        }
+        for index in 0..program.len(){
+            if let Some(target) = branch_target(program, text_address, index){
+                leaders[target] = true;
+            }
+            if is_terminator(program, index) && index + 1 < program.len(){
+                leaders[index + 1] = true;
+            }
+        }
+
+        let starts: Vec<usize> = (0..program.len()).filter(|x| leaders[*x]).collect();
+        let block_of = |index: usize| starts.partition_point(|x| *x <= index) - 1;
+
+        let mut blocks = Vec::new();
+        for (block, start) in starts.iter().enumerate(){
+            let end = starts.get(block + 1).copied().unwrap_or(program.len());
+            let last = end - 1;
+            let (instruction, mode, _) = &program[last];
+
+            let mut successors = Vec::new();
+            if let Some(target) = branch_target(program, text_address, last){
+                successors.push(Edge{target: block_of(target), kind: EdgeKind::Branch});
+            }
+
+            let dynamic = matches!(instruction, Instructions::RET | Instructions::IRET) ||
+                (*instruction == Instructions::JMP && *mode != InstructionMode::Immediate);
+            if falls_through(program, last) && end < program.len(){
+                successors.push(Edge{target: block + 1, kind: EdgeKind::FallThrough});
+            }
+
+            let address = text_address.wrapping_add(*start as u32 * 4);
+            blocks.push(BasicBlock{start: *start, address, end, successors, dynamic});
+        }
+
+        Cfg{
+            program: program.to_vec(),
+            text_address,
+            blocks,
+        }
+    }
+
+    // The block holding the instruction at a code address
+    pub fn block_at(&self, address: u32) -> Option<usize>{
+        let index = address.checked_sub(self.text_address)? as usize / 4;
+        if index >= self.program.len(){
+            return None;
+        }
+
+        Some(self.blocks.partition_point(|x| x.start <= index) - 1)
+    }
+
+    pub fn predecessors(&self, block: usize) -> Vec<usize>{
+        self.blocks.iter().enumerate()
+            .filter(|(_, x)| x.successors.iter().any(|edge| edge.target == block))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // The instructions of a block, with their code addresses
+    pub fn instructions(&self, block: usize) -> impl Iterator<Item = (u32, &(Instructions, InstructionMode, Vec<Parameter>))>{
+        let block = &self.blocks[block];
+        (block.start..block.end).map(move |x| (self.text_address.wrapping_add(x as u32 * 4), &self.program[x]))
+    }
+
+    // Graphviz DOT, with the disassembly of each block in its node
+    pub fn to_dot(&self) -> String{
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for block in 0..self.blocks.len(){
+            let mut label = String::new();
+            for (address, (instruction, mode, args)) in self.instructions(block){
+                label += &format!("0x{:04X}: {}\\l", address, disassemble(*instruction, *mode, args));
+            }
+            if self.blocks[block].dynamic{
+                label += "(dynamic jump)\\l";
+            }
+
+            dot += &format!("    b{} [label=\"{}\"];\n", block, label.replace('"', "\\\""));
+        }
+
+        for (block, x) in self.blocks.iter().enumerate(){
+            for edge in &x.successors{
+                let style = match edge.kind{
+                    EdgeKind::Branch => "solid",
+                    EdgeKind::FallThrough => "dashed",
+                };
+                dot += &format!("    b{} -> b{} [style={}];\n", block, edge.target, style);
+            }
+        }
+
+        dot += "}\n";
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::utils::decode_instructions_at;
+
+    // A loop, a call to a function, SYS EXIT, and unreachable code after the function, placed at 0x100
+    const TEXT: [u32; 12] = [
+        0x03401001, 3,     // 0x100: SET R1, 3
+        0x06401001, 1,     // 0x104: SUB R1, 1
+        0x1E400001, 0x104, // 0x108: IFNE 0x104
+        0x20400001, 0x118, // 0x10C: CALL 0x118
+        0x03400020,        // 0x110: SET R0, EXIT
+        0x25000000,        // 0x114: SYS
+        0x21000000,        // 0x118: RET
+        0xFF000000,        // 0x11C: invalid opcode
+    ];
+
+    fn build() -> Cfg{
+        Cfg::build(&decode_instructions_at(&TEXT, 0x100), 0x100)
+    }
+
+    fn edge(target: usize, kind: EdgeKind) -> Edge{
+        Edge{target, kind}
+    }
+
+    #[test]
+    fn blocks_split_at_targets_and_terminators(){
+        let cfg = build();
+
+        let bounds: Vec<(usize, usize, u32)> = cfg.blocks.iter().map(|x| (x.start, x.end, x.address)).collect();
+        assert_eq!(bounds, vec![(0, 1, 0x100), (1, 3, 0x104), (3, 4, 0x10C), (4, 6, 0x110), (6, 7, 0x118), (7, 8, 0x11C)]);
+
+        assert_eq!(cfg.block_at(0x108), Some(1));
+        assert_eq!(cfg.block_at(0x11C), Some(5));
+        assert_eq!(cfg.block_at(0x0), None);
+        assert_eq!(cfg.block_at(0x120), None);
+        assert_eq!(cfg.instructions(1).map(|(address, _)| address).collect::<Vec<_>>(), vec![0x104, 0x108]);
+    }
+
+    #[test]
+    fn edges_follow_branches_and_fall_through(){
+        let cfg = build();
+
+        assert_eq!(cfg.blocks[0].successors, vec![edge(1, EdgeKind::FallThrough)]);
+        assert_eq!(cfg.blocks[1].successors, vec![edge(1, EdgeKind::Branch), edge(2, EdgeKind::FallThrough)]);
+        assert_eq!(cfg.blocks[2].successors, vec![edge(4, EdgeKind::Branch), edge(3, EdgeKind::FallThrough)]);
+
+        // SYS EXIT, RET and invalid opcodes don't go to the next block
+        assert_eq!(cfg.blocks[3].successors, vec![]);
+        assert_eq!(cfg.blocks[4].successors, vec![]);
+        assert_eq!(cfg.blocks[5].successors, vec![]);
+
+        let dynamic: Vec<bool> = cfg.blocks.iter().map(|x| x.dynamic).collect();
+        assert_eq!(dynamic, vec![false, false, false, false, true, false]);
+        assert_eq!(cfg.predecessors(1), vec![0, 1]);
+        assert_eq!(cfg.predecessors(5), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn other_syscalls_fall_through(){
+        let text = [0x03400010, 0x25000000, 0x00000000]; // SET R0, 1; SYS; HLT
+        let cfg = Cfg::build(&decode_instructions_at(&text, 0), 0);
+
+        assert_eq!(cfg.blocks.len(), 1);
+        assert_eq!(cfg.blocks[0].end, 3);
+    }
+
+    #[test]
+    fn dot_has_a_node_per_block_and_styled_edges(){
+        let dot = build().to_dot();
+
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert_eq!(dot.matches("[label=").count(), 6);
+        assert!(dot.contains("    b1 [label=\"0x0104: "));
+        assert!(dot.contains("\\l0x0108: "));
+        assert!(dot.contains("(dynamic jump)\\l\"];\n"));
+        assert!(dot.contains("    b1 -> b1 [style=solid];\n"));
+        assert!(dot.contains("    b1 -> b2 [style=dashed];\n"));
+        assert!(dot.contains("    b2 -> b4 [style=solid];\n"));
+        assert_eq!(dot.matches(" -> ").count(), 5);
+    }
+}
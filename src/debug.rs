@@ -0,0 +1,206 @@
+use std::path::Path;
+
+// Debug Info:
+//
+// Debug info lives in a text file next to the program, with the extension changed to .dbg
+// (main.dbv -> main.dbg). The linker writes one with `dbv link -g`, and assemblers can add lines.
+// Each line of the file is one entry, and lines starting with # are comments:
+//
+// text <code address> <name>           A label in the program (eg: a function or a loop)
+// data <memory address> <name>         A variable
+// line <code address> <line> <file>    The source line the instructions from this address came from
+//
+// Addresses can be decimal or hex (0x...). Code addresses count instructions, see executable.rs.
+// An address is described by the closest label and source line at or before it: `loop+0x8 (main.s:42)`
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DebugInfo{
+    text: Vec<(u32, String)>,         // Sorted by address
+    data: Vec<(u32, String)>,         // Sorted by address
+    lines: Vec<(u32, String, u32)>,   // Address, file, line. Sorted by address
+}
+
+impl DebugInfo{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn load<T>(path: &T) -> Result<Self, String> where T: AsRef<Path> + ?Sized{
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String>{
+        let mut info = DebugInfo::new();
+
+        for (number, line) in text.lines().enumerate(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#'){
+                continue;
+            }
+
+            let error = || format!("Invalid debug info on line {}: {}", number + 1, line);
+
+            let mut fields = line.splitn(3, char::is_whitespace);
+            let kind = fields.next().unwrap_or("");
+            let address = fields.next().and_then(parse_address).ok_or_else(error)?;
+            let rest = fields.next().map(str::trim).filter(|x| !x.is_empty()).ok_or_else(error)?;
+
+            match kind{
+                "text" => info.add_text_symbol(address, rest),
+                "data" => info.add_data_symbol(address, rest),
+                "line" => {
+                    // The file name is the rest of the line, so it can have spaces
+                    let (source_line, file) = rest.split_once(char::is_whitespace).ok_or_else(error)?;
+                    let source_line = source_line.parse().map_err(|_| error())?;
+                    info.add_line(address, file.trim(), source_line);
+                },
+                _ => return Err(error()),
+            }
+        }
+
+        Ok(info)
+    }
+
+    pub fn to_text(&self) -> String{
+        let mut text = String::from("# dbv debug info\n");
+        for (address, name) in &self.text{
+            text += &format!("text 0x{:08X} {}\n", address, name);
+        }
+        for (address, name) in &self.data{
+            text += &format!("data 0x{:08X} {}\n", address, name);
+        }
+        for (address, file, line) in &self.lines{
+            text += &format!("line 0x{:08X} {} {}\n", address, line, file);
+        }
+        text
+    }
+
+    pub fn add_text_symbol(&mut self, address: u32, name: &str){
+        let index = self.text.partition_point(|x| x.0 <= address);
+        self.text.insert(index, (address, String::from(name)));
+    }
+
+    pub fn add_data_symbol(&mut self, address: u32, name: &str){
+        let index = self.data.partition_point(|x| x.0 <= address);
+        self.data.insert(index, (address, String::from(name)));
+    }
+
+    pub fn add_line(&mut self, address: u32, file: &str, line: u32){
+        let index = self.lines.partition_point(|x| x.0 <= address);
+        self.lines.insert(index, (address, String::from(file), line));
+    }
+
+    // Move every address by `base`, for a program loaded at a base (see Executable::relocate)
+    pub fn relocate(&mut self, base: u32){
+        for (address, _) in self.text.iter_mut().chain(self.data.iter_mut()){
+            *address = address.wrapping_add(base);
+        }
+        for (address, _, _) in &mut self.lines{
+            *address = address.wrapping_add(base);
+        }
+    }
+
+    // The text symbol and offset for a code address, eg: `loop+0x8 (main.s:42)`
+    pub fn symbolize_code(&self, address: u32) -> String{
+        let mut result = match closest(&self.text, address, |x| x.0){
+            Some((start, name)) => with_offset(name, address - start),
+            None => format!("0x{:08X}", address),
+        };
+
+        if let Some((_, file, line)) = closest(&self.lines, address, |x| x.0){
+            result += &format!(" ({}:{})", file, line);
+        }
+
+        result
+    }
+
+    // Every variable, in address order
+    pub fn data_symbols(&self) -> impl Iterator<Item = (u32, &str)>{
+        self.data.iter().map(|(address, name)| (*address, name.as_str()))
+    }
+
+    // The data symbol and offset for a memory address, if there's one before it
+    pub fn symbolize_data(&self, address: u32) -> Option<String>{
+        closest(&self.data, address, |x| x.0).map(|(start, name)| with_offset(name, address - start))
+    }
+}
+
+// The last entry at or before `address`
+fn closest<T, F>(entries: &[T], address: u32, key: F) -> Option<&T> where F: Fn(&T) -> u32{
+    let index = entries.partition_point(|x| key(x) <= address);
+    index.checked_sub(1).map(|i| &entries[i])
+}
+
+fn with_offset(name: &str, offset: u32) -> String{
+    if offset == 0{
+        String::from(name)
+    }else{
+        format!("{}+0x{:X}", name, offset)
+    }
+}
+
+fn parse_address(text: &str) -> Option<u32>{
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")){
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const TEXT: &str = "
+        # Made by hand
+        text 0 _start
+        text 0x10 loop
+        data 0x2000 counter
+        data 8196 table
+        line 0 1 main.s
+        line 0x14 7 lib/my file.s
+    ";
+
+    #[test]
+    fn parse_reads_every_kind_of_entry(){
+        let info = DebugInfo::parse(TEXT).unwrap();
+
+        assert_eq!(info.text, vec![(0, String::from("_start")), (0x10, String::from("loop"))]);
+        assert_eq!(info.data_symbols().collect::<Vec<_>>(), vec![(0x2000, "counter"), (0x2004, "table")]);
+        assert_eq!(info.lines, vec![(0, String::from("main.s"), 1), (0x14, String::from("lib/my file.s"), 7)]);
+
+        // Writing it out gives the same info back
+        assert_eq!(DebugInfo::parse(&info.to_text()), Ok(info));
+    }
+
+    #[test]
+    fn parse_refuses_invalid_lines(){
+        for line in ["text", "text 0x10", "text zero main", "line 0x10 main.s", "line 0x10 x main.s", "stack 0x10 main"]{
+            assert_eq!(DebugInfo::parse(line), Err(format!("Invalid debug info on line 1: {}", line)));
+        }
+    }
+
+    #[test]
+    fn code_addresses_use_the_closest_label_and_line(){
+        let info = DebugInfo::parse(TEXT).unwrap();
+
+        assert_eq!(info.symbolize_code(0), "_start (main.s:1)");
+        assert_eq!(info.symbolize_code(0xC), "_start+0xC (main.s:1)");
+        assert_eq!(info.symbolize_code(0x10), "loop (main.s:1)");
+        assert_eq!(info.symbolize_code(0x18), "loop+0x8 (lib/my file.s:7)");
+        assert_eq!(DebugInfo::new().symbolize_code(0x18), "0x00000018");
+    }
+
+    #[test]
+    fn data_addresses_use_the_closest_variable(){
+        let mut info = DebugInfo::parse(TEXT).unwrap();
+
+        assert_eq!(info.symbolize_data(0x1FFC), None);
+        assert_eq!(info.symbolize_data(0x2000), Some(String::from("counter")));
+        assert_eq!(info.symbolize_data(0x2006), Some(String::from("table+0x2")));
+
+        info.relocate(0x100);
+        assert_eq!(info.symbolize_data(0x2100), Some(String::from("counter")));
+        assert_eq!(info.symbolize_code(0x110), "loop (main.s:1)");
+    }
+}
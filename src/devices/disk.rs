@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::memory::Memory;
+use super::{Device, register_read, register_write};
+
+// Disk Registers (32-bit):
+//
+// | Offset | Name    | Access     | Description                                                       |
+// | 0x00   | SECTOR  | Read/Write | Sector number to transfer                                         |
+// | 0x04   | BUFFER  | Read/Write | Physical address of the 512 byte buffer in memory                 |
+// | 0x08   | COMMAND | Write      | 1: Read the sector into the buffer, 2: Write the buffer to it     |
+// | 0x0C   | STATUS  | Read/Write | Bit 0: Busy, Bit 1: Error, Bit 2: Done - write 1 to clear Done     |
+// | 0x10   | SECTORS | Read       | Size of the image in sectors                                      |
+// | 0x14   | CONTROL | Read/Write | Bit 0: Raise an interrupt when a command is done                  |
+//
+// Writing COMMAND sets Busy, and the transfer happens on the next tick. When it's finished,
+// Busy is cleared and Done is set, along with Error if the sector or buffer was out of range,
+// or the disk is read only.
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub const DISK_SECTOR: u32 = 0x00;
+pub const DISK_BUFFER: u32 = 0x04;
+pub const DISK_COMMAND: u32 = 0x08;
+pub const DISK_STATUS: u32 = 0x0C;
+pub const DISK_SECTORS: u32 = 0x10;
+pub const DISK_CONTROL: u32 = 0x14;
+
+pub const DISK_COMMAND_READ: u32 = 0x1;
+pub const DISK_COMMAND_WRITE: u32 = 0x2;
+
+pub const DISK_STATUS_BUSY: u32 = 0x1;
+pub const DISK_STATUS_ERROR: u32 = 0x2;
+pub const DISK_STATUS_DONE: u32 = 0x4;
+
+pub const DISK_CONTROL_IRQ: u32 = 0x1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiskMode{
+    ReadWrite, // Writes go to the image
+    ReadOnly,  // Writes fail with an error
+    Overlay,   // Writes are kept in memory, and the image is never changed
+}
+
+pub struct Disk{
+    image: File,
+    mode: DiskMode,
+    sectors: u32,
+    overlay: HashMap<u32, Vec<u8>>, // Sectors written in Overlay mode
+
+    sector: u32,
+    buffer: u32,
+    command: Option<u32>, // Waiting to run on the next tick
+    status: u32,
+    control: u32,
+}
+
+impl Disk{
+    pub fn open<T>(file_path: &T, mode: DiskMode) -> std::io::Result<Self> where T: AsRef<Path> + ?Sized{
+        let image = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(file_path)?;
+
+        // A partial last sector still counts, and reads back padded with zeros
+        let length = image.metadata()?.len() as usize;
+        let sectors = length.div_ceil(SECTOR_SIZE) as u32;
+
+        Ok(Disk{
+            image,
+            mode,
+            sectors,
+            overlay: HashMap::new(),
+
+            sector: 0,
+            buffer: 0,
+            command: None,
+            status: 0,
+            control: 0,
+        })
+    }
+
+    fn read_sector(&mut self, sector: u32) -> std::io::Result<Vec<u8>>{
+        if let Some(data) = self.overlay.get(&sector){
+            return Ok(data.clone());
+        }
+
+        let mut data = vec![0u8; SECTOR_SIZE];
+        self.image.seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE as u64))?;
+
+        let mut done = 0;
+        while done < SECTOR_SIZE{
+            match self.image.read(&mut data[done..])?{
+                0 => break,
+                length => done += length,
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn write_sector(&mut self, sector: u32, data: Vec<u8>) -> std::io::Result<()>{
+        match self.mode{
+            DiskMode::ReadWrite => {
+                self.image.seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE as u64))?;
+                self.image.write_all(&data)
+            },
+            DiskMode::ReadOnly => Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Disk is read only")),
+            DiskMode::Overlay => {
+                self.overlay.insert(sector, data);
+                Ok(())
+            },
+        }
+    }
+
+    // Run a command, returning false if it failed
+    fn transfer(&mut self, command: u32, memory: &mut Memory) -> bool{
+        let buffer = self.buffer as usize;
+        if self.sector >= self.sectors || buffer + SECTOR_SIZE > memory.size(){
+            return false;
+        }
+
+        match command{
+            DISK_COMMAND_READ => {
+                match self.read_sector(self.sector){
+                    Ok(data) => {
+                        memory.write_bytes(buffer, &data);
+                        true
+                    },
+                    Err(_) => false,
+                }
+            },
+            DISK_COMMAND_WRITE => {
+                let mut data = vec![0u8; SECTOR_SIZE];
+                memory.read_bytes(buffer, &mut data);
+
+                self.write_sector(self.sector, data).is_ok()
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Device for Disk{
+    fn size(&self) -> u32{
+        0x18
+    }
+
+    fn read(&mut self, offset: u32, width: u32) -> u32{
+        let value = match offset & !0x3{
+            DISK_SECTOR => self.sector,
+            DISK_BUFFER => self.buffer,
+            DISK_STATUS => self.status,
+            DISK_SECTORS => self.sectors,
+            DISK_CONTROL => self.control,
+            _ => 0,
+        };
+
+        register_read(value, offset, width)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32){
+        match offset & !0x3{
+            DISK_SECTOR => self.sector = register_write(self.sector, offset, width, value),
+            DISK_BUFFER => self.buffer = register_write(self.buffer, offset, width, value),
+            // Commands issued while busy are ignored
+            DISK_COMMAND if self.status & DISK_STATUS_BUSY == 0 => {
+                self.command = Some(value);
+                self.status = DISK_STATUS_BUSY;
+            },
+            DISK_STATUS => {
+                // Write 1 to clear Done
+                let cleared = register_write(0, offset, width, value) & DISK_STATUS_DONE;
+                self.status &= !cleared;
+            },
+            DISK_CONTROL => self.control = register_write(self.control, offset, width, value),
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, memory: &mut Memory){
+        if let Some(command) = self.command.take(){
+            self.status = DISK_STATUS_DONE;
+            if !self.transfer(command, memory){
+                self.status |= DISK_STATUS_ERROR;
+            }
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool{
+        self.control & DISK_CONTROL_IRQ != 0 && self.status & DISK_STATUS_DONE != 0
+    }
+
+    // A forked disk never writes to the image - it starts with a copy of the parent's overlay
+    fn fork(&self) -> Box<dyn Device>{
+        let image = self.image.try_clone().expect("Couldn't share the disk image with the forked VM");
+
+        Box::new(Disk{
+            image,
+            mode: if self.mode == DiskMode::ReadOnly { DiskMode::ReadOnly } else { DiskMode::Overlay },
+            sectors: self.sectors,
+            overlay: self.overlay.clone(),
+
+            sector: self.sector,
+            buffer: self.buffer,
+            command: self.command,
+            status: self.status,
+            control: self.control,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::path::PathBuf;
+
+    // An image of two sectors, filled with 0x11 and 0x22, and a partial third sector of 0x33
+    fn image(name: &str) -> PathBuf{
+        let path = std::env::temp_dir().join(format!("dbv-disk-{}-{}.img", name, std::process::id()));
+        let data = [[0x11; SECTOR_SIZE], [0x22; SECTOR_SIZE]].concat();
+        std::fs::write(&path, [data, vec![0x33; 10]].concat()).unwrap();
+        path
+    }
+
+    // Read the image, and remove it
+    fn finish(path: PathBuf) -> Vec<u8>{
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        data
+    }
+
+    // Run a command to completion, returning the status
+    fn transfer(disk: &mut Disk, memory: &mut Memory, command: u32, sector: u32, buffer: u32) -> u32{
+        disk.write(DISK_STATUS, 4, DISK_STATUS_DONE);
+        disk.write(DISK_SECTOR, 4, sector);
+        disk.write(DISK_BUFFER, 4, buffer);
+        disk.write(DISK_COMMAND, 4, command);
+        assert_eq!(disk.read(DISK_STATUS, 4), DISK_STATUS_BUSY);
+
+        disk.tick(memory);
+        disk.read(DISK_STATUS, 4)
+    }
+
+    #[test]
+    fn sectors_are_read_and_written(){
+        let path = image("read-write");
+        let mut disk = Disk::open(&path, DiskMode::ReadWrite).unwrap();
+        let mut memory = Memory::new();
+        assert_eq!(disk.read(DISK_SECTORS, 4), 3);
+
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 1, 0x3000), DISK_STATUS_DONE);
+        assert_eq!(memory.read::<u32>(0x3000), 0x22222222);
+        assert_eq!(memory.read::<u8>(0x3000 + SECTOR_SIZE - 1), 0x22);
+        assert_eq!(memory.read::<u8>(0x3000 + SECTOR_SIZE), 0x01);
+
+        // The partial last sector is padded with zeros
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 2, 0x3000), DISK_STATUS_DONE);
+        assert_eq!(memory.read::<u8>(0x3000 + 9), 0x33);
+        assert_eq!(memory.read::<u8>(0x3000 + 10), 0);
+
+        memory.fill(0x4000, SECTOR_SIZE, 0xAB);
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_WRITE, 0, 0x4000), DISK_STATUS_DONE);
+
+        drop(disk);
+        let data = finish(path);
+        assert_eq!(data[..SECTOR_SIZE], [0xAB; SECTOR_SIZE]);
+        assert_eq!(data[SECTOR_SIZE], 0x22);
+    }
+
+    #[test]
+    fn out_of_range_transfers_are_errors(){
+        let path = image("range");
+        let mut disk = Disk::open(&path, DiskMode::ReadWrite).unwrap();
+        let mut memory = Memory::new();
+        let last = (memory.size() - SECTOR_SIZE) as u32;
+
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 3, 0x3000), DISK_STATUS_DONE | DISK_STATUS_ERROR);
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 0, last + 1), DISK_STATUS_DONE | DISK_STATUS_ERROR);
+        assert_eq!(transfer(&mut disk, &mut memory, 3, 0, 0x3000), DISK_STATUS_DONE | DISK_STATUS_ERROR);
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 0, last), DISK_STATUS_DONE);
+
+        // The next command clears the error
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 0, 0x3000), DISK_STATUS_DONE);
+
+        drop(disk);
+        finish(path);
+    }
+
+    #[test]
+    fn read_only_disks_refuse_writes(){
+        let path = image("read-only");
+        let mut disk = Disk::open(&path, DiskMode::ReadOnly).unwrap();
+        let mut memory = Memory::new();
+
+        memory.fill(0x4000, SECTOR_SIZE, 0xAB);
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_WRITE, 0, 0x4000), DISK_STATUS_DONE | DISK_STATUS_ERROR);
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 0, 0x3000), DISK_STATUS_DONE);
+        assert_eq!(memory.read::<u8>(0x3000), 0x11);
+
+        drop(disk);
+        assert_eq!(finish(path)[0], 0x11);
+    }
+
+    #[test]
+    fn overlays_leave_the_image_alone(){
+        let path = image("overlay");
+        let mut disk = Disk::open(&path, DiskMode::Overlay).unwrap();
+        let mut memory = Memory::new();
+
+        memory.fill(0x4000, SECTOR_SIZE, 0xAB);
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_WRITE, 1, 0x4000), DISK_STATUS_DONE);
+        assert_eq!(transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 1, 0x3000), DISK_STATUS_DONE);
+        assert_eq!(memory.read::<u8>(0x3000), 0xAB);
+
+        drop(disk);
+        assert_eq!(finish(path)[SECTOR_SIZE..SECTOR_SIZE * 2], [0x22; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn done_raises_an_interrupt_when_enabled(){
+        let path = image("interrupt");
+        let mut disk = Disk::open(&path, DiskMode::ReadOnly).unwrap();
+        let mut memory = Memory::new();
+
+        transfer(&mut disk, &mut memory, DISK_COMMAND_READ, 0, 0x3000);
+        assert!(!disk.interrupt_pending());
+
+        disk.write(DISK_CONTROL, 4, DISK_CONTROL_IRQ);
+        assert!(disk.interrupt_pending());
+        disk.write(DISK_STATUS, 4, DISK_STATUS_DONE);
+        assert!(!disk.interrupt_pending());
+
+        drop(disk);
+        finish(path);
+    }
+}
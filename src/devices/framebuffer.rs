@@ -0,0 +1,341 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::memory::{Memory, MEMORY_SIZE};
+use super::{Device, register_read, register_write};
+
+// Framebuffer Registers (32-bit):
+//
+// | Offset | Name    | Access     | Description                                                         |
+// | 0x00   | ADDRESS | Read/Write | Physical address of the pixels in memory                            |
+// | 0x04   | WIDTH   | Read/Write | Width in pixels                                                     |
+// | 0x08   | HEIGHT  | Read/Write | Height in pixels                                                    |
+// | 0x0C   | FORMAT  | Read/Write | 0: 8-bit palette indices, 1: RGB565 (16-bit little endian)          |
+// | 0x10   | PALETTE | Read/Write | Physical address of 256 32-bit 0x00RRGGBB entries (0: RGB332)       |
+// | 0x14   | PRESENT | Read/Write | Write to present the frame, read for the number of frames presented |
+// | 0x18   | STATUS  | Read       | Bit 0: Error - the last frame couldn't be written                   |
+//
+// Pixels are stored row by row with no padding. Presenting copies the frame out of
+// memory on the next tick, and writes it to the output as an RGB image.
+//
+// A WIDTH, HEIGHT or FORMAT write that would make the frame bigger than memory is ignored, and
+// sets the error bit.
+//
+// Frames are written as numbered files (frame_00000.ppm, frame_00001.ppm, ...) in a directory,
+// or all appended to one multi-image PPM file, which ffmpeg and netpbm can read as an animation.
+
+pub const FRAMEBUFFER_ADDRESS: u32 = 0x00;
+pub const FRAMEBUFFER_WIDTH: u32 = 0x04;
+pub const FRAMEBUFFER_HEIGHT: u32 = 0x08;
+pub const FRAMEBUFFER_FORMAT: u32 = 0x0C;
+pub const FRAMEBUFFER_PALETTE: u32 = 0x10;
+pub const FRAMEBUFFER_PRESENT: u32 = 0x14;
+pub const FRAMEBUFFER_STATUS: u32 = 0x18;
+
+pub const FRAMEBUFFER_FORMAT_PALETTE: u32 = 0x0;
+pub const FRAMEBUFFER_FORMAT_RGB565: u32 = 0x1;
+
+pub const FRAMEBUFFER_STATUS_ERROR: u32 = 0x1;
+
+pub enum FrameOutput{
+    Ppm(PathBuf),       // Numbered PPM files in a directory
+    Png(PathBuf),       // Numbered PNG files in a directory
+    PpmStream(PathBuf), // Every frame appended to one PPM file
+}
+
+pub struct Framebuffer{
+    output: Option<FrameOutput>, // Frames are only counted without an output
+
+    address: u32,
+    width: u32,
+    height: u32,
+    format: u32,
+    palette: u32,
+    frames: u32,
+    status: u32,
+
+    present: bool, // A frame is waiting to be presented on the next tick
+}
+
+impl Framebuffer{
+    pub fn new(output: FrameOutput, address: u32, width: u32, height: u32, format: u32) -> Self{
+        Framebuffer{
+            output: Some(output),
+
+            address,
+            width,
+            height,
+            format,
+            palette: 0,
+            frames: 0,
+            status: 0,
+
+            present: false,
+        }
+    }
+
+    // Convert the frame in memory to 8-bit RGB. Returns None if it doesn't fit in memory
+    fn capture(&self, memory: &Memory) -> Option<Vec<u8>>{
+        let size = frame_size(self.width, self.height, self.format)?;
+        if self.address as usize + size > memory.size(){
+            return None;
+        }
+
+        let mut raw = vec![0u8; size];
+        memory.read_bytes(self.address as usize, &mut raw);
+
+        let mut rgb = Vec::with_capacity(self.width as usize * self.height as usize * 3);
+        if self.format == FRAMEBUFFER_FORMAT_RGB565{
+            for pixel in raw.chunks_exact(2){
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]) as u32;
+                rgb.push((((value >> 11) & 0x1F) * 255 / 31) as u8);
+                rgb.push((((value >> 5) & 0x3F) * 255 / 63) as u8);
+                rgb.push(((value & 0x1F) * 255 / 31) as u8);
+            }
+        }else{
+            let palette = self.load_palette(memory)?;
+            for index in raw{
+                rgb.extend_from_slice(&palette[index as usize]);
+            }
+        }
+
+        Some(rgb)
+    }
+
+    fn load_palette(&self, memory: &Memory) -> Option<Vec<[u8; 3]>>{
+        if self.palette == 0{
+            // RGB332 - 3 bits of red, 3 of green, 2 of blue
+            return Some((0..256u32).map(|index| [
+                (((index >> 5) & 0x7) * 255 / 7) as u8,
+                (((index >> 2) & 0x7) * 255 / 7) as u8,
+                ((index & 0x3) * 255 / 3) as u8,
+            ]).collect());
+        }
+
+        if self.palette as usize + 256 * 4 > memory.size(){
+            return None;
+        }
+
+        Some((0..256).map(|index| {
+            let entry = memory.get_memory(self.palette as usize + index * 4);
+            [(entry >> 16) as u8, (entry >> 8) as u8, entry as u8]
+        }).collect())
+    }
+
+    fn write_frame(&self, rgb: &[u8]) -> std::io::Result<()>{
+        let output = match &self.output{
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        match output{
+            FrameOutput::Ppm(directory) => {
+                let mut file = File::create(directory.join(format!("frame_{:05}.ppm", self.frames)))?;
+                file.write_all(&encode_ppm(self.width, self.height, rgb))
+            },
+            FrameOutput::Png(directory) => {
+                let mut file = File::create(directory.join(format!("frame_{:05}.png", self.frames)))?;
+                file.write_all(&encode_png(self.width, self.height, rgb))
+            },
+            FrameOutput::PpmStream(path) => {
+                // The first frame replaces whatever was in the file
+                let mut file = if self.frames == 0{
+                    File::create(path)?
+                }else{
+                    File::options().append(true).open(path)?
+                };
+                file.write_all(&encode_ppm(self.width, self.height, rgb))
+            },
+        }
+    }
+}
+
+impl Device for Framebuffer{
+    fn size(&self) -> u32{
+        0x1C
+    }
+
+    fn read(&mut self, offset: u32, width: u32) -> u32{
+        let value = match offset & !0x3{
+            FRAMEBUFFER_ADDRESS => self.address,
+            FRAMEBUFFER_WIDTH => self.width,
+            FRAMEBUFFER_HEIGHT => self.height,
+            FRAMEBUFFER_FORMAT => self.format,
+            FRAMEBUFFER_PALETTE => self.palette,
+            FRAMEBUFFER_PRESENT => self.frames,
+            FRAMEBUFFER_STATUS => self.status,
+            _ => 0,
+        };
+
+        register_read(value, offset, width)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32){
+        match offset & !0x3{
+            FRAMEBUFFER_ADDRESS => self.address = register_write(self.address, offset, width, value),
+            FRAMEBUFFER_WIDTH | FRAMEBUFFER_HEIGHT | FRAMEBUFFER_FORMAT => {
+                let mut mode = (self.width, self.height, self.format);
+                match offset & !0x3{
+                    FRAMEBUFFER_WIDTH => mode.0 = register_write(self.width, offset, width, value),
+                    FRAMEBUFFER_HEIGHT => mode.1 = register_write(self.height, offset, width, value),
+                    _ => mode.2 = register_write(self.format, offset, width, value),
+                }
+
+                if frame_size(mode.0, mode.1, mode.2).is_some(){
+                    (self.width, self.height, self.format) = mode;
+                }else{
+                    self.status |= FRAMEBUFFER_STATUS_ERROR;
+                }
+            },
+            FRAMEBUFFER_PALETTE => self.palette = register_write(self.palette, offset, width, value),
+            FRAMEBUFFER_PRESENT => self.present = true,
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, memory: &mut Memory){
+        if !self.present{
+            return;
+        }
+        self.present = false;
+
+        let result = match self.capture(memory){
+            Some(rgb) => self.write_frame(&rgb).map_err(|e| e.to_string()),
+            None => Err(String::from("frame is outside of memory")),
+        };
+
+        match result{
+            Ok(_) => self.status &= !FRAMEBUFFER_STATUS_ERROR,
+            Err(e) => {
+                eprintln!("Couldn't write frame {}: {}", self.frames, e);
+                self.status |= FRAMEBUFFER_STATUS_ERROR;
+            }
+        }
+
+        self.frames += 1;
+    }
+
+    // A forked framebuffer doesn't write frames, so children don't overwrite the parent's output
+    fn fork(&self) -> Box<dyn Device>{
+        Box::new(Framebuffer{
+            output: None,
+
+            address: self.address,
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            palette: self.palette,
+            frames: self.frames,
+            status: self.status,
+
+            present: self.present,
+        })
+    }
+}
+
+// Bytes of pixels in memory for a mode, or None if that's more than memory could hold
+fn frame_size(width: u32, height: u32, format: u32) -> Option<usize>{
+    let bytes_per_pixel = if format == FRAMEBUFFER_FORMAT_RGB565 { 2 } else { 1 };
+    let size = (width as usize).checked_mul(height as usize)?.checked_mul(bytes_per_pixel)?;
+
+    (size <= MEMORY_SIZE).then_some(size)
+}
+
+pub fn encode_ppm(width: u32, height: u32, rgb: &[u8]) -> Vec<u8>{
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    data.extend_from_slice(rgb);
+    data
+}
+
+// PNG with the image data in uncompressed deflate blocks - bigger files, but no compressor needed
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8>{
+    let mut data = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits per channel, RGB, deflate, no filter, no interlace
+    png_chunk(&mut data, b"IHDR", &header);
+
+    // Every row starts with its filter type (0 = none)
+    let row_length = (width as usize).saturating_mul(3);
+    let capacity = row_length.checked_add(1).and_then(|x| x.checked_mul(height as usize)).unwrap_or(0).min(rgb.len() + height as usize);
+    let mut raw = Vec::with_capacity(capacity);
+    for row in rgb.chunks(row_length.max(1)).take(height as usize){
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream: header, stored blocks of up to 65535 bytes, adler32 of the raw data
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xFFFF).collect();
+    if blocks.is_empty(){
+        zlib.extend_from_slice(&[0x1, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    for (i, block) in blocks.iter().enumerate(){
+        let last = i == blocks.len() - 1;
+        let length = block.len() as u16;
+
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&length.to_le_bytes());
+        zlib.extend_from_slice(&(!length).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+    png_chunk(&mut data, b"IDAT", &zlib);
+
+    png_chunk(&mut data, b"IEND", &[]);
+    data
+}
+
+fn png_chunk(data: &mut Vec<u8>, kind: &[u8; 4], contents: &[u8]){
+    data.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+
+    let start = data.len();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(contents);
+
+    let crc = crc32(&data[start..]);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+pub fn crc32(data: &[u8]) -> u32{
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data{
+        crc ^= *byte as u32;
+        for _ in 0..8{
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32{
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data{
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn modes_bigger_than_memory_are_refused(){
+        let mut framebuffer = Framebuffer::new(FrameOutput::Ppm(PathBuf::from("frames")), 0x80000, 320, 200, FRAMEBUFFER_FORMAT_RGB565);
+
+        framebuffer.write(FRAMEBUFFER_WIDTH, 4, 0xFFFFFFFF);
+        assert_eq!(framebuffer.read(FRAMEBUFFER_WIDTH, 4), 320);
+        assert_eq!(framebuffer.read(FRAMEBUFFER_STATUS, 4), FRAMEBUFFER_STATUS_ERROR);
+
+        framebuffer.write(FRAMEBUFFER_HEIGHT, 4, 400);
+        assert_eq!(framebuffer.read(FRAMEBUFFER_HEIGHT, 4), 400);
+
+        // Even the largest mode can be encoded without overflowing
+        assert!(!encode_png(u32::MAX, u32::MAX, &[]).is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+pub mod disk;
+pub mod framebuffer;
+pub mod rng;
+pub mod timer;
+pub mod uart;
+
+use crate::memory::Memory;
+
+// Device Design:
+//
+// Devices are memory mapped - each one owns `size()` bytes of the physical address
+// space starting at the base address it's attached at (see layout.rs). Loads and stores
+// that land in a device's range are passed to it instead of Memory, after MMU translation.
+//
+// Devices get the offset into their range and the access width (1, 2 or 4 bytes).
+//
+// Every device is ticked once per executed instruction, which is the VM's unit of time.
+//
+// Devices attached with an interrupt line raise that line while `interrupt_pending()` is true.
+
+pub trait Device: Send{
+    // Number of bytes of address space the device's registers take up
+    fn size(&self) -> u32;
+
+    fn read(&mut self, offset: u32, width: u32) -> u32;
+    fn write(&mut self, offset: u32, width: u32, value: u32);
+
+    // Called once per executed instruction. Devices that transfer data to or from memory do it here
+    fn tick(&mut self, _memory: &mut Memory){}
+
+    // Whether the device is asserting its interrupt line
+    fn interrupt_pending(&self) -> bool{
+        false
+    }
+
+    // Create a device for a forked VM
+    fn fork(&self) -> Box<dyn Device>;
+}
+
+struct Attached{
+    base: u32,
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+pub struct Bus{
+    devices: Vec<Attached>,
+}
+
+impl Default for Bus{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl Bus{
+    pub fn new() -> Self{
+        Bus{
+            devices: Vec::new(),
+        }
+    }
+
+    // Attach a device at `base`, replacing any device already attached there
+    pub fn attach(&mut self, base: u32, device: Box<dyn Device>){
+        self.detach(base);
+        self.devices.push(Attached{base, irq: None, device});
+    }
+
+    // Attach a device that can raise interrupt line `irq`
+    pub fn attach_with_irq(&mut self, base: u32, irq: u32, device: Box<dyn Device>){
+        self.detach(base);
+        self.devices.push(Attached{base, irq: Some(irq), device});
+    }
+
+    pub fn detach(&mut self, base: u32){
+        self.devices.retain(|attached| attached.base != base);
+    }
+
+    // Find the device mapped at a physical address, and the offset into it
+    pub fn find(&mut self, address: usize) -> Option<(&mut Box<dyn Device>, u32)>{
+        let address = address as u32;
+        self.devices.iter_mut()
+            .find(|attached| address >= attached.base && address - attached.base < attached.device.size())
+            .map(|attached| (&mut attached.device, address - attached.base))
+    }
+
+    pub fn tick(&mut self, memory: &mut Memory){
+        for attached in self.devices.iter_mut(){
+            attached.device.tick(memory);
+        }
+    }
+
+    // Bitmask of the interrupt lines devices are currently raising
+    pub fn pending_interrupts(&self) -> u32{
+        self.devices.iter()
+            .filter(|attached| attached.device.interrupt_pending())
+            .filter_map(|attached| attached.irq)
+            .fold(0, |lines, irq| lines | (1 << irq))
+    }
+
+    pub fn fork(&self) -> Self{
+        Bus{
+            devices: self.devices.iter().map(|attached| Attached{
+                base: attached.base,
+                irq: attached.irq,
+                device: attached.device.fork(),
+            }).collect(),
+        }
+    }
+}
+
+// Read `width` bytes of a 32-bit device register, for accesses that don't start at the register's first byte
+pub fn register_read(register: u32, offset: u32, width: u32) -> u32{
+    let value = register >> ((offset & 0x3) * 8);
+    match width{
+        1 => value & 0xFF,
+        2 => value & 0xFFFF,
+        _ => value,
+    }
+}
+
+// Merge a `width` byte write into a 32-bit device register, returning the new register value
+pub fn register_write(register: u32, offset: u32, width: u32, value: u32) -> u32{
+    let shift = (offset & 0x3) * 8;
+    let mask = match width{
+        1 => 0xFF,
+        2 => 0xFFFF,
+        _ => 0xFFFFFFFF,
+    } << shift;
+
+    (register & !mask) | ((value << shift) & mask)
+}
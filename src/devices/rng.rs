@@ -0,0 +1,88 @@
+use super::{Device, register_read};
+
+// RNG Registers (32-bit):
+//
+// | Offset | Name | Access | Description                          |
+// | 0x0    | DATA | Read   | The next random value                |
+//
+// Values come from a SplitMix64 generator, so the same seed always gives the same sequence.
+// Forked VMs continue from the parent's state - reseed them to give each its own sequence.
+
+pub const RNG_DATA: u32 = 0x0;
+
+#[derive(Clone)]
+pub struct Rng{
+    state: u64,
+}
+
+impl Rng{
+    pub fn new(seed: u64) -> Self{
+        Rng{
+            state: seed,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64{
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Device for Rng{
+    fn size(&self) -> u32{
+        0x4
+    }
+
+    fn read(&mut self, offset: u32, width: u32) -> u32{
+        match offset & !0x3{
+            RNG_DATA => register_read((self.next_u64() >> 32) as u32, offset, width),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _offset: u32, _width: u32, _value: u32){}
+
+    fn fork(&self) -> Box<dyn Device>{
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn values_are_splitmix64(){
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xE220A8397B1DCDAF);
+        assert_eq!(rng.next_u64(), 0x6E789E6AA1B965F4);
+        assert_eq!(rng.next_u64(), 0x06C45D188009454F);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_sequence(){
+        let sequence = |seed: u64| -> Vec<u32>{
+            let mut rng = Rng::new(seed);
+            (0..8).map(|_| rng.read(RNG_DATA, 4)).collect()
+        };
+
+        assert_eq!(sequence(42), sequence(42));
+        assert_ne!(sequence(42), sequence(43));
+
+        // DATA is the high half of each value
+        assert_eq!(sequence(0)[0], 0xE220A839);
+    }
+
+    #[test]
+    fn forks_continue_the_sequence(){
+        let mut rng = Rng::new(7);
+        rng.read(RNG_DATA, 4);
+
+        let mut fork = rng.fork();
+        assert_eq!(fork.read(RNG_DATA, 4), rng.read(RNG_DATA, 4));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::memory::Memory;
+use super::{Device, register_read, register_write};
+
+// Timer Registers (32-bit):
+//
+// | Offset | Name    | Access     | Description                                                  |
+// | 0x0    | CONTROL | Read/Write | Bit 0: Enable, Bit 1: Periodic (else one-shot), Bit 2: IRQ   |
+// | 0x4    | RELOAD  | Read/Write | Value the counter starts from                                |
+// | 0x8    | COUNT   | Read/Write | Instructions left until the timer expires                    |
+// | 0xC    | STATUS  | Read/Write | Bit 0: Expired - write 1 to clear                            |
+//
+// The timer counts executed instructions rather than host time, so runs are deterministic.
+// Enabling the timer loads COUNT from RELOAD. When COUNT reaches 0 the timer sets
+// Expired, then either reloads (periodic) or disables itself (one-shot).
+// With the IRQ bit set, an expired timer asserts its interrupt line until Expired is cleared.
+
+pub const TIMER_CONTROL: u32 = 0x0;
+pub const TIMER_RELOAD: u32 = 0x4;
+pub const TIMER_COUNT: u32 = 0x8;
+pub const TIMER_STATUS: u32 = 0xC;
+
+pub const TIMER_CONTROL_ENABLE: u32 = 0x1;
+pub const TIMER_CONTROL_PERIODIC: u32 = 0x2;
+pub const TIMER_CONTROL_IRQ: u32 = 0x4;
+
+pub const TIMER_STATUS_EXPIRED: u32 = 0x1;
+
+#[derive(Clone)]
+pub struct Timer{
+    control: u32,
+    reload: u32,
+    count: u32,
+    status: u32,
+}
+
+impl Default for Timer{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl Timer{
+    pub fn new() -> Self{
+        Timer{
+            control: 0,
+            reload: 0,
+            count: 0,
+            status: 0,
+        }
+    }
+}
+
+impl Device for Timer{
+    fn size(&self) -> u32{
+        0x10
+    }
+
+    fn read(&mut self, offset: u32, width: u32) -> u32{
+        let value = match offset & !0x3{
+            TIMER_CONTROL => self.control,
+            TIMER_RELOAD => self.reload,
+            TIMER_COUNT => self.count,
+            TIMER_STATUS => self.status,
+            _ => 0,
+        };
+
+        register_read(value, offset, width)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32){
+        match offset & !0x3{
+            TIMER_CONTROL => {
+                let was_enabled = self.control & TIMER_CONTROL_ENABLE != 0;
+                self.control = register_write(self.control, offset, width, value);
+
+                if !was_enabled && self.control & TIMER_CONTROL_ENABLE != 0{
+                    self.count = self.reload;
+                }
+            },
+            TIMER_RELOAD => self.reload = register_write(self.reload, offset, width, value),
+            TIMER_COUNT => self.count = register_write(self.count, offset, width, value),
+            TIMER_STATUS => {
+                // Write 1 to clear
+                let cleared = register_write(0, offset, width, value);
+                self.status &= !cleared;
+            },
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self, _memory: &mut Memory){
+        if self.control & TIMER_CONTROL_ENABLE == 0{
+            return;
+        }
+
+        self.count = self.count.saturating_sub(1);
+        if self.count == 0{
+            self.status |= TIMER_STATUS_EXPIRED;
+
+            if self.control & TIMER_CONTROL_PERIODIC != 0{
+                self.count = self.reload;
+            }else{
+                self.control &= !TIMER_CONTROL_ENABLE;
+            }
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool{
+        self.control & TIMER_CONTROL_IRQ != 0 && self.status & TIMER_STATUS_EXPIRED != 0
+    }
+
+    fn fork(&self) -> Box<dyn Device>{
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // A timer started with `control`, that expires every `reload` ticks
+    fn start(control: u32, reload: u32) -> Timer{
+        let mut timer = Timer::new();
+        timer.write(TIMER_RELOAD, 4, reload);
+        timer.write(TIMER_CONTROL, 4, control | TIMER_CONTROL_ENABLE);
+        timer
+    }
+
+    fn tick(timer: &mut Timer, ticks: u32){
+        let mut memory = Memory::new();
+        for _ in 0..ticks{
+            timer.tick(&mut memory);
+        }
+    }
+
+    #[test]
+    fn one_shot_timers_stop_when_they_expire(){
+        let mut timer = start(0, 3);
+        assert_eq!(timer.read(TIMER_COUNT, 4), 3);
+
+        tick(&mut timer, 2);
+        assert_eq!(timer.read(TIMER_COUNT, 4), 1);
+        assert_eq!(timer.read(TIMER_STATUS, 4), 0);
+
+        tick(&mut timer, 1);
+        assert_eq!(timer.read(TIMER_STATUS, 4), TIMER_STATUS_EXPIRED);
+        assert_eq!(timer.read(TIMER_CONTROL, 4) & TIMER_CONTROL_ENABLE, 0);
+
+        // Stopped, so clearing Expired is final
+        timer.write(TIMER_STATUS, 4, TIMER_STATUS_EXPIRED);
+        tick(&mut timer, 10);
+        assert_eq!(timer.read(TIMER_STATUS, 4), 0);
+        assert_eq!(timer.read(TIMER_COUNT, 4), 0);
+    }
+
+    #[test]
+    fn periodic_timers_reload(){
+        let mut timer = start(TIMER_CONTROL_PERIODIC, 3);
+
+        tick(&mut timer, 3);
+        assert_eq!(timer.read(TIMER_STATUS, 4), TIMER_STATUS_EXPIRED);
+        assert_eq!(timer.read(TIMER_COUNT, 4), 3);
+        assert_ne!(timer.read(TIMER_CONTROL, 4) & TIMER_CONTROL_ENABLE, 0);
+
+        // Writing 0 leaves Expired alone, writing 1 clears it
+        timer.write(TIMER_STATUS, 4, 0);
+        assert_eq!(timer.read(TIMER_STATUS, 4), TIMER_STATUS_EXPIRED);
+        timer.write(TIMER_STATUS, 4, TIMER_STATUS_EXPIRED);
+        assert_eq!(timer.read(TIMER_STATUS, 4), 0);
+
+        tick(&mut timer, 2);
+        assert_eq!(timer.read(TIMER_STATUS, 4), 0);
+        tick(&mut timer, 1);
+        assert_eq!(timer.read(TIMER_STATUS, 4), TIMER_STATUS_EXPIRED);
+    }
+
+    #[test]
+    fn interrupts_are_pending_until_cleared(){
+        let mut timer = start(TIMER_CONTROL_PERIODIC | TIMER_CONTROL_IRQ, 2);
+        assert!(!timer.interrupt_pending());
+
+        tick(&mut timer, 2);
+        assert!(timer.interrupt_pending());
+        tick(&mut timer, 1);
+        assert!(timer.interrupt_pending());
+
+        timer.write(TIMER_STATUS, 4, TIMER_STATUS_EXPIRED);
+        assert!(!timer.interrupt_pending());
+
+        // Without the IRQ bit, expiring doesn't interrupt
+        let mut timer = start(0, 1);
+        tick(&mut timer, 1);
+        assert_eq!(timer.read(TIMER_STATUS, 4), TIMER_STATUS_EXPIRED);
+        assert!(!timer.interrupt_pending());
+    }
+}
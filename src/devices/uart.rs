@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+
+use super::Device;
+
+// UART Registers:
+//
+// | Offset | Name   | Access     | Description                                        |
+// | 0x0    | DATA   | Read/Write | Write to transmit a byte, read to receive one      |
+// | 0x1    | STATUS | Read       | Bit 0: RX ready, Bit 1: TX busy, Bit 2: RX closed  |
+//
+// Transmitted bytes are written to the host's stdout. Received bytes come from either
+// stdin or a scripted input file. Reading DATA when RX isn't ready returns 0.
+//
+// Example polling loop (R1 = UART_BASE + 1):
+//   wait: LD8 R2, R1
+//         AND R2, R2, 0x1
+//         CMP R2, R0
+//         IF wait
+
+pub const UART_DATA: u32 = 0x0;
+pub const UART_STATUS: u32 = 0x1;
+
+pub const UART_STATUS_RX_READY: u32 = 0x1;
+pub const UART_STATUS_TX_BUSY: u32 = 0x2;
+pub const UART_STATUS_RX_CLOSED: u32 = 0x4;
+
+enum UartInput{
+    // stdin is read on a background thread, so checking the status never blocks.
+    // The thread is only started on the first receive, so the UART doesn't take stdin from the READ syscall.
+    // It's shared with forked VMs
+    Stdin(Arc<Mutex<Option<Receiver<u8>>>>),
+    Script(VecDeque<u8>),
+}
+
+pub struct Uart{
+    input: UartInput,
+    received: Option<u8>, // The byte in the receive register, if there is one
+    closed: bool,         // The input has ended
+}
+
+impl Default for Uart{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl Uart{
+    // A UART receiving from stdin
+    pub fn new() -> Self{
+        Uart{
+            input: UartInput::Stdin(Arc::new(Mutex::new(None))),
+            received: None,
+            closed: false,
+        }
+    }
+
+    // A UART receiving the bytes of a file, then reporting RX closed
+    pub fn from_file<T>(file_path: &T) -> std::io::Result<Self> where T: AsRef<Path> + ?Sized{
+        let data = std::fs::read(file_path)?;
+        Ok(Self::from_bytes(&data))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Self{
+        Uart{
+            input: UartInput::Script(data.iter().copied().collect()),
+            received: None,
+            closed: false,
+        }
+    }
+
+    // Move the next input byte into the receive register, if it's empty and a byte is available
+    fn poll(&mut self){
+        if self.received.is_some() || self.closed{
+            return;
+        }
+
+        match &mut self.input{
+            UartInput::Stdin(receiver) => {
+                let mut receiver = receiver.lock().unwrap();
+                let receiver = receiver.get_or_insert_with(spawn_stdin_reader);
+
+                match receiver.try_recv(){
+                    Ok(byte) => self.received = Some(byte),
+                    Err(TryRecvError::Empty) => {},
+                    Err(TryRecvError::Disconnected) => self.closed = true,
+                }
+            },
+            UartInput::Script(data) => {
+                match data.pop_front(){
+                    Some(byte) => self.received = Some(byte),
+                    None => self.closed = true,
+                }
+            },
+        }
+    }
+}
+
+fn spawn_stdin_reader() -> Receiver<u8>{
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        for byte in std::io::stdin().lock().bytes(){
+            match byte{
+                Ok(byte) => if sender.send(byte).is_err(){ break },
+                Err(_) => break,
+            }
+        }
+    });
+
+    receiver
+}
+
+impl Device for Uart{
+    fn size(&self) -> u32{
+        0x2
+    }
+
+    fn read(&mut self, offset: u32, _width: u32) -> u32{
+        self.poll();
+
+        match offset{
+            UART_DATA => self.received.take().unwrap_or(0) as u32,
+            UART_STATUS => {
+                let mut status = 0;
+                if self.received.is_some(){
+                    status |= UART_STATUS_RX_READY;
+                }
+                // Transmitting is synchronous, so TX is never busy
+                if self.closed{
+                    status |= UART_STATUS_RX_CLOSED;
+                }
+                status
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _width: u32, value: u32){
+        if offset == UART_DATA{
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(&[value as u8]).and_then(|_| stdout.flush());
+        }
+    }
+
+    fn fork(&self) -> Box<dyn Device>{
+        let input = match &self.input{
+            UartInput::Stdin(receiver) => UartInput::Stdin(Arc::clone(receiver)),
+            UartInput::Script(data) => UartInput::Script(data.clone()),
+        };
+
+        Box::new(Uart{
+            input,
+            received: self.received,
+            closed: self.closed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn scripted_input_is_received_then_closed(){
+        let mut uart = Uart::from_bytes(b"hi");
+
+        assert_eq!(uart.read(UART_STATUS, 1), UART_STATUS_RX_READY);
+        assert_eq!(uart.read(UART_DATA, 1), b'h' as u32);
+        assert_eq!(uart.read(UART_STATUS, 1), UART_STATUS_RX_READY);
+        assert_eq!(uart.read(UART_DATA, 1), b'i' as u32);
+
+        // Once the input runs out, there's nothing more to read
+        assert_eq!(uart.read(UART_STATUS, 1), UART_STATUS_RX_CLOSED);
+        assert_eq!(uart.read(UART_DATA, 1), 0);
+        assert_eq!(uart.read(UART_STATUS, 1), UART_STATUS_RX_CLOSED);
+    }
+
+    #[test]
+    fn empty_input_is_closed_straight_away(){
+        let mut uart = Uart::from_bytes(&[]);
+        assert_eq!(uart.read(UART_DATA, 1), 0);
+        assert_eq!(uart.read(UART_STATUS, 1), UART_STATUS_RX_CLOSED);
+    }
+
+    #[test]
+    fn forks_get_the_rest_of_the_input(){
+        let mut uart = Uart::from_bytes(b"abc");
+        assert_eq!(uart.read(UART_DATA, 1), b'a' as u32);
+        assert_eq!(uart.read(UART_STATUS, 1), UART_STATUS_RX_READY);
+
+        let mut fork = uart.fork();
+        assert_eq!(fork.read(UART_DATA, 1), b'b' as u32);
+        assert_eq!(fork.read(UART_DATA, 1), b'c' as u32);
+        assert_eq!(uart.read(UART_DATA, 1), b'b' as u32);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::layout::HEAP_START;
+use crate::object::Section;
+use crate::signature;
+use crate::utils::{code_addresses, is_extended};
+
+// Executable Format:
+//
+// A .dbv executable starts with a 52 byte header. Every field is a 32-bit big endian word,
+// the same as the instructions.
+//
+// | Offset | Field          | Description                                                    |
+// | 0x00   | MAGIC          | "DBV\0"                                                        |
+// | 0x04   | VERSION        | Format version (high 16 bits), ISA version (low 16 bits)       |
+// | 0x08   | ENTRY          | Code address of the first instruction to run                   |
+// | 0x0C   | FLAGS          | FLAG_RELOCATABLE, FLAG_TRAILER, the other bits must be 0       |
+// | 0x10   | TEXT           | File offset, size and load address of the instructions         |
+// | 0x1C   | DATA           | File offset, size and load address of the initialised data     |
+// | 0x28   | BSS            | 0, size and load address of the zeroed data (not in the file)  |
+//
+// Text is decoded into the program, and is placed at its code address (usually 0) - jumps and the
+// entry point are code addresses (4 per instruction, extension words don't count), not memory
+// addresses. The text can't go past MAX_CODE_ADDRESS, as the VM keeps a slot for every code address below
+// it. Data is copied into memory, and BSS is cleared to zero. Both must fit in the static data
+// region below the heap (see layout.rs) and can't overlap.
+//
+// Relocatable executables have FLAG_RELOCATABLE set, and a relocation table after the last
+// section (aligned to 4 bytes): a count, then 2 words for each relocation:
+//
+// | Section (1 = text, 2 = data) | Offset (of the word to patch) |
+//
+// Each relocated word holds an absolute address - a code address or a memory address. Moving
+// the program by `base` adds `base` to every one of them, and to the entry point and the address
+// of every section, so it can be loaded alongside other programs (see VirtualMachine::load_library).
+// Position independent programs use relative jumps (see utils::is_relative) and have an empty table.
+//
+// Raw files (big endian instruction words with no header) can still be loaded with `from_raw`,
+// starting at code address 0. They have no version, so they're taken to be for the current ISA.
+
+pub const EXECUTABLE_MAGIC: u32 = 0x44425600; // "DBV\0"
+pub const FORMAT_VERSION: u32 = 1;
+// Version 2 gave SD, SD16 and SD8 the operands of the loads: `SD R1, [R2]` stores R1 at the address
+// in R2. Version 1 stored the second register at the address in the first, and RegisterIndirect
+// copied memory to memory, so version 1 programs would store to the wrong addresses and are refused
+pub const ISA_VERSION: u32 = 2;
+
+pub const HEADER_SIZE: usize = 0x34;
+
+pub const MAX_CODE_ADDRESS: u64 = 0x100000; // Room for 256Ki instructions
+
+pub const FLAG_RELOCATABLE: u32 = 0x1; // There's a relocation table after the sections
+pub const FLAG_TRAILER: u32 = 0x2; // The file ends with a hash and signature, see signature.rs
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Relocation{
+    pub section: Section, // Text or Data
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Executable{
+    pub entry: u32,
+
+    pub text_address: u32,
+    pub text: Vec<u32>,
+
+    pub data_address: u32,
+    pub data: Vec<u8>,
+
+    pub bss_address: u32,
+    pub bss_size: u32,
+
+    pub relocations: Option<Vec<Relocation>>, // None if the program can only run where it was linked
+}
+
+impl Executable{
+    // Build an executable out of a headerless file
+    pub fn from_raw(bytes: &[u8]) -> Result<Self, &'static str>{
+        Ok(Executable{
+            text: words(bytes)?,
+            ..Default::default()
+        })
+    }
+
+    // Parse a .dbv file. If it has a trailer, its hash has to match
+    pub fn parse(bytes: &[u8]) -> Result<Self, &'static str>{
+        let (bytes, _) = signature::check(bytes)?;
+        if bytes.len() < HEADER_SIZE{
+            return Err("File is too small to be an executable (use --raw for headerless programs)");
+        }
+
+        let header = words(&bytes[..HEADER_SIZE])?;
+        if header[0] != EXECUTABLE_MAGIC{
+            return Err("Not a dbv executable (use --raw for headerless programs)");
+        }
+        if header[1] >> 16 != FORMAT_VERSION{
+            return Err("Unsupported executable format version");
+        }
+        if header[1] & 0xFFFF == 1{
+            return Err("Program is for ISA version 1, where SD has its operands the other way round - it has to be rebuilt");
+        }
+        if header[1] & 0xFFFF != ISA_VERSION{
+            return Err("Unsupported ISA version");
+        }
+        if header[3] & !(FLAG_RELOCATABLE | FLAG_TRAILER) != 0 || header[10] != 0{
+            return Err("Reserved header fields must be 0");
+        }
+
+        let section = |offset: u32, size: u32| -> Result<&[u8], &'static str>{
+            let start = offset as usize;
+            let end = start.checked_add(size as usize).ok_or("Section is outside of the file")?;
+            if size != 0 && (start < HEADER_SIZE || end > bytes.len()){
+                return Err("Section is outside of the file");
+            }
+            Ok(&bytes[start.min(bytes.len())..end.min(bytes.len())])
+        };
+
+        let relocations = if header[3] & FLAG_RELOCATABLE != 0{
+            // The table follows whichever section ends last
+            let text_end = header[4] as usize + header[5] as usize;
+            let data_end = if header[8] == 0 { 0 } else { header[7] as usize + header[8] as usize };
+            let start = HEADER_SIZE.max(text_end).max(data_end).next_multiple_of(4);
+
+            let count = bytes.get(start..start + 4).ok_or("Relocation table is outside of the file")?;
+            let count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]) as usize;
+            let table = count.checked_mul(8).and_then(|x| bytes.get(start + 4..start + 4 + x)).ok_or("Relocation table is outside of the file")?;
+
+            let mut relocations = Vec::new();
+            for relocation in words(table)?.chunks_exact(2){
+                relocations.push(Relocation{
+                    section: match relocation[0]{
+                        1 => Section::Text,
+                        2 => Section::Data,
+                        _ => return Err("Relocations can only patch text or data"),
+                    },
+                    offset: relocation[1],
+                });
+            }
+            Some(relocations)
+        }else{
+            None
+        };
+
+        let executable = Executable{
+            entry: header[2],
+
+            text_address: header[6],
+            text: words(section(header[4], header[5])?)?,
+
+            data_address: header[9],
+            data: section(header[7], header[8])?.to_vec(),
+
+            bss_address: header[12],
+            bss_size: header[11],
+
+            relocations,
+        };
+
+        executable.validate()?;
+        Ok(executable)
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str>{
+        if self.text.is_empty(){
+            return Err("Executable has no instructions");
+        }
+        if !self.text_address.is_multiple_of(4){
+            return Err("Text must be loaded at a multiple of 4");
+        }
+        let addresses = code_addresses(&self.text);
+        if addresses.last() != Some(&None) && self.text.last().is_some_and(|x| is_extended(*x)){
+            return Err("The last instruction is missing its extension word");
+        }
+        let instructions = addresses.iter().flatten().count() as u64;
+        if self.text_address as u64 + instructions * 4 > MAX_CODE_ADDRESS{
+            return Err("Text doesn't fit below the highest code address");
+        }
+        if !self.entry.is_multiple_of(4) || self.entry < self.text_address || (self.entry - self.text_address) as u64 / 4 >= instructions{
+            return Err("Entry point is outside of the text section");
+        }
+
+        let data_end = self.data_address as u64 + self.data.len() as u64;
+        let bss_end = self.bss_address as u64 + self.bss_size as u64;
+        if data_end > HEAP_START as u64 || bss_end > HEAP_START as u64{
+            return Err("Data and BSS must fit below the heap");
+        }
+        if !self.data.is_empty() && self.bss_size != 0 && (self.data_address as u64) < bss_end && (self.bss_address as u64) < data_end{
+            return Err("Data and BSS sections overlap");
+        }
+
+        for relocation in self.relocations.iter().flatten(){
+            let size = match relocation.section{
+                Section::Text => self.text.len() as u64 * 4,
+                Section::Data => self.data.len() as u64,
+                _ => return Err("Relocations can only patch text or data"),
+            };
+            if !relocation.offset.is_multiple_of(4) || relocation.offset as u64 + 4 > size{
+                return Err("Relocation is outside of its section");
+            }
+        }
+
+        Ok(())
+    }
+
+    // A copy of the executable moved by `base`, with its relocations applied. It's validated
+    // either way, as executables don't have to come from `parse`
+    pub fn relocate(&self, base: u32) -> Result<Self, &'static str>{
+        if base == 0{
+            self.validate()?;
+            return Ok(self.clone());
+        }
+        let relocations = self.relocations.as_ref().ok_or("Executable isn't relocatable")?;
+        if !base.is_multiple_of(4){
+            return Err("Programs can only be moved by a multiple of 4");
+        }
+
+        let mut executable = self.clone();
+        for relocation in relocations{
+            let offset = relocation.offset as usize;
+            match relocation.section{
+                Section::Text => executable.text[offset / 4] = executable.text[offset / 4].wrapping_add(base),
+                _ => {
+                    let data = &mut executable.data[offset..offset + 4];
+                    let word = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    data.copy_from_slice(&word.wrapping_add(base).to_le_bytes());
+                },
+            }
+        }
+
+        let moved = |address: u32| address.checked_add(base).ok_or("Program doesn't fit at that address");
+        executable.entry = moved(self.entry)?;
+        executable.text_address = moved(self.text_address)?;
+        // Empty sections stay where they are, so they're never outside of memory
+        if !self.data.is_empty(){
+            executable.data_address = moved(self.data_address)?;
+        }
+        if self.bss_size != 0{
+            executable.bss_address = moved(self.bss_address)?;
+        }
+
+        executable.validate()?;
+        Ok(executable)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let text_offset = HEADER_SIZE as u32;
+        let data_offset = text_offset + self.text.len() as u32 * 4;
+
+        let header = [
+            EXECUTABLE_MAGIC,
+            (FORMAT_VERSION << 16) | ISA_VERSION,
+            self.entry,
+            if self.relocations.is_some() { FLAG_RELOCATABLE } else { 0 },
+            text_offset, self.text.len() as u32 * 4, self.text_address,
+            if self.data.is_empty() { 0 } else { data_offset }, self.data.len() as u32, self.data_address,
+            0, self.bss_size, self.bss_address,
+        ];
+
+        let mut bytes = Vec::new();
+        for word in header.iter().chain(self.text.iter()){
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.data);
+
+        if let Some(relocations) = &self.relocations{
+            bytes.resize(bytes.len().next_multiple_of(4), 0);
+            bytes.extend_from_slice(&(relocations.len() as u32).to_be_bytes());
+            for relocation in relocations{
+                bytes.extend_from_slice(&(relocation.section as u32).to_be_bytes());
+                bytes.extend_from_slice(&relocation.offset.to_be_bytes());
+            }
+        }
+
+        bytes
+    }
+}
+
+// Split bytes into big endian words, refusing a truncated last word
+pub fn words(bytes: &[u8]) -> Result<Vec<u32>, &'static str>{
+    if !bytes.len().is_multiple_of(4){
+        return Err("Program size isn't a multiple of 4 bytes");
+    }
+
+    Ok(bytes.chunks_exact(4).map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]])).collect())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn executable() -> Executable{
+        Executable{
+            entry: 4,
+
+            text_address: 0,
+            text: vec![0x1F400001, 4, 0x00000000], // JMP 4, HLT
+
+            data_address: 0x2000,
+            data: vec![0x08, 0x20, 0x00, 0x00, 0xAA],
+
+            bss_address: 0x2008,
+            bss_size: 0x10,
+
+            relocations: Some(vec![
+                Relocation{section: Section::Text, offset: 4},
+                Relocation{section: Section::Data, offset: 0},
+            ]),
+        }
+    }
+
+    #[test]
+    fn executables_round_trip(){
+        let executable = executable();
+        assert_eq!(Executable::parse(&executable.to_bytes()), Ok(executable.clone()));
+
+        let fixed = Executable{relocations: None, ..executable};
+        assert_eq!(Executable::parse(&fixed.to_bytes()), Ok(fixed));
+    }
+
+    #[test]
+    fn relocating_moves_every_address(){
+        let moved = executable().relocate(0x100).unwrap();
+
+        assert_eq!(moved.entry, 0x104);
+        assert_eq!(moved.text_address, 0x100);
+        assert_eq!(moved.text, vec![0x1F400001, 0x104, 0x00000000]);
+        assert_eq!(moved.data_address, 0x2100);
+        assert_eq!(moved.data[..4], 0x2108u32.to_le_bytes());
+        assert_eq!(moved.bss_address, 0x2108);
+
+        assert!(Executable{relocations: None, ..executable()}.relocate(0x100).is_err());
+    }
+
+    #[test]
+    fn text_has_to_fit_below_the_highest_code_address(){
+        let high = Executable{entry: 0x80000000, text_address: 0x80000000, relocations: None, ..executable()};
+        assert_eq!(high.validate(), Err("Text doesn't fit below the highest code address"));
+        assert_eq!(Executable::parse(&high.to_bytes()), Err("Text doesn't fit below the highest code address"));
+
+        // Moving a program has to keep it there too
+        let code = Executable{text: vec![0x1F400001, 4, 0x00000000], relocations: Some(Vec::new()), ..Default::default()};
+        let last = MAX_CODE_ADDRESS as u32 - 8;
+        assert!(code.relocate(last).is_ok());
+        assert_eq!(code.relocate(last + 4), Err("Text doesn't fit below the highest code address"));
+    }
+
+    #[test]
+    fn damaged_executables_are_refused(){
+        let bytes = executable().to_bytes();
+
+        assert!(Executable::parse(&bytes[..HEADER_SIZE - 1]).is_err());
+        assert!(Executable::parse(&bytes[..bytes.len() - 4]).is_err()); // Relocation table cut short
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(Executable::parse(&bad_magic).is_err());
+
+        let truncated = Executable{text: vec![0x1F400001], ..Default::default()}; // JMP, with no target
+        assert_eq!(truncated.validate(), Err("The last instruction is missing its extension word"));
+        assert_eq!(Executable::from_raw(&0x1F400001u32.to_be_bytes()).unwrap().relocate(0), Err("The last instruction is missing its extension word"));
+
+        let mut old_isa = bytes.clone();
+        old_isa[0x04..0x08].copy_from_slice(&((FORMAT_VERSION << 16) | 1).to_be_bytes());
+        assert!(Executable::parse(&old_isa).unwrap_err().starts_with("Program is for ISA version 1"));
+
+        let mut bad_entry = bytes;
+        bad_entry[0x08..0x0C].copy_from_slice(&12u32.to_be_bytes());
+        assert_eq!(Executable::parse(&bad_entry), Err("Entry point is outside of the text section"));
+    }
+}
@@ -0,0 +1,17 @@
+// Faults are raised by `execute` when an instruction can't complete.
+// Exceptions (see interrupts.rs) are delivered to a guest handler if there is one,
+// otherwise they - and every other fault - stop the VM and are returned from `run`
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault{
+    InvalidOpcode{ opcode: u32 },            // The instruction's opcode isn't defined, or it names a control register that isn't
+    DivideByZero,                            // DIV or MOD with a divisor of 0
+    PageFault{ address: u32, write: bool }, // The MMU couldn't translate `address`
+    BusError{ address: u32 },                // A physical access outside of memory
+    Misaligned{ address: u32, width: u32 },  // A 16 or 32-bit access wasn't aligned, and the alignment policy is Fault
+    HeapOverflow{ requested: u32 },          // BRK/SBRK asked for a break outside the heap region
+    InvalidSyscall{ number: u32 },           // SYS was executed with an unknown call number
+    UnknownHostFunction{ id: u32 },          // HCALL was executed with an id that isn't registered
+    HostFunction{ id: u32, pc: u32, message: String }, // A host function returned an error. `pc` is the byte address of the HCALL
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+// Sandboxed Filesystem:
+//
+// Guest programs can only open files inside a root directory chosen by the host (--fs-root).
+// Guest paths are always relative to the root - a leading '/' is the root itself, and '..'
+// can't climb above it. Symbolic links that lead outside of the root are refused as well.
+//
+// Open files are identified by file descriptors handed out by the VM, starting at 3
+// (0, 1 and 2 are stdin, stdout and stderr).
+
+pub const FIRST_FILE_DESCRIPTOR: u32 = 3;
+
+// Flags for the OPEN system call
+pub const OPEN_READ: u32 = 0x1;
+pub const OPEN_WRITE: u32 = 0x2;
+pub const OPEN_CREATE: u32 = 0x4;
+pub const OPEN_TRUNCATE: u32 = 0x8;
+pub const OPEN_APPEND: u32 = 0x10;
+
+// Error codes returned by file system calls, negated in R0 (eg: -2 for NotFound)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsError{
+    Error = 1,            // Anything not covered below
+    NotFound = 2,
+    PermissionDenied = 3, // Includes paths outside of the sandbox, and no sandbox being configured
+    BadDescriptor = 4,
+    InvalidArgument = 5,
+    AlreadyExists = 6,
+    IoError = 7,
+}
+
+impl FsError{
+    // The value returned to the guest
+    pub fn code(self) -> u32{
+        (-(self as i32)) as u32
+    }
+}
+
+impl From<std::io::Error> for FsError{
+    fn from(error: std::io::Error) -> Self{
+        match error.kind(){
+            std::io::ErrorKind::NotFound => FsError::NotFound,
+            std::io::ErrorKind::PermissionDenied => FsError::PermissionDenied,
+            std::io::ErrorKind::AlreadyExists => FsError::AlreadyExists,
+            std::io::ErrorKind::InvalidInput => FsError::InvalidArgument,
+            _ => FsError::IoError,
+        }
+    }
+}
+
+pub struct FileTable{
+    root: Option<PathBuf>, // Canonical path of the sandbox. No files can be opened without one
+    files: HashMap<u32, File>,
+    next_descriptor: u32,
+}
+
+impl Default for FileTable{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl FileTable{
+    pub fn new() -> Self{
+        FileTable{
+            root: None,
+            files: HashMap::new(),
+            next_descriptor: FIRST_FILE_DESCRIPTOR,
+        }
+    }
+
+    pub fn set_root<T>(&mut self, root: &T) -> std::io::Result<()> where T: AsRef<Path> + ?Sized{
+        self.root = Some(root.as_ref().canonicalize()?);
+        Ok(())
+    }
+
+    // Turn a guest path into a host path inside the sandbox
+    fn resolve(&self, path: &str) -> Result<PathBuf, FsError>{
+        let root = self.root.as_ref().ok_or(FsError::PermissionDenied)?;
+
+        // Normalise the path without touching the filesystem, refusing to go above the root
+        let mut relative = PathBuf::new();
+        for component in Path::new(path).components(){
+            match component{
+                Component::Normal(name) => relative.push(name),
+                Component::ParentDir => {
+                    if !relative.pop(){
+                        return Err(FsError::PermissionDenied);
+                    }
+                },
+                Component::RootDir | Component::CurDir => {},
+                Component::Prefix(_) => return Err(FsError::PermissionDenied),
+            }
+        }
+
+        if relative.as_os_str().is_empty(){
+            return Err(FsError::InvalidArgument);
+        }
+
+        // Follow any links, and check the real path is still in the sandbox.
+        // New files don't exist yet, so check their directory instead. A link that leads nowhere
+        // can't be checked, and creating the file would follow it, so it's refused
+        let host_path = root.join(&relative);
+        let real_path = match host_path.canonicalize(){
+            Ok(x) => x,
+            Err(_) => {
+                if host_path.symlink_metadata().is_ok(){
+                    return Err(FsError::PermissionDenied);
+                }
+                let parent = host_path.parent().ok_or(FsError::InvalidArgument)?.canonicalize()?;
+                parent.join(host_path.file_name().ok_or(FsError::InvalidArgument)?)
+            }
+        };
+
+        if !real_path.starts_with(root){
+            return Err(FsError::PermissionDenied);
+        }
+
+        Ok(real_path)
+    }
+
+    pub fn open(&mut self, path: &str, flags: u32) -> Result<u32, FsError>{
+        if flags & (OPEN_READ | OPEN_WRITE | OPEN_APPEND) == 0{
+            return Err(FsError::InvalidArgument);
+        }
+
+        let host_path = self.resolve(path)?;
+        if host_path.is_dir(){
+            return Err(FsError::InvalidArgument);
+        }
+
+        let file = OpenOptions::new()
+            .read(flags & OPEN_READ != 0)
+            .write(flags & OPEN_WRITE != 0)
+            .append(flags & OPEN_APPEND != 0)
+            .create(flags & OPEN_CREATE != 0)
+            .truncate(flags & OPEN_TRUNCATE != 0)
+            .open(host_path)?;
+
+        let descriptor = self.next_descriptor;
+        self.next_descriptor += 1;
+        self.files.insert(descriptor, file);
+
+        Ok(descriptor)
+    }
+
+    pub fn close(&mut self, descriptor: u32) -> Result<(), FsError>{
+        self.files.remove(&descriptor).map(|_| ()).ok_or(FsError::BadDescriptor)
+    }
+
+    pub fn read(&mut self, descriptor: u32, buffer: &mut [u8]) -> Result<usize, FsError>{
+        let file = self.files.get_mut(&descriptor).ok_or(FsError::BadDescriptor)?;
+        Ok(file.read(buffer)?)
+    }
+
+    pub fn write(&mut self, descriptor: u32, data: &[u8]) -> Result<usize, FsError>{
+        let file = self.files.get_mut(&descriptor).ok_or(FsError::BadDescriptor)?;
+        file.write_all(data)?;
+        Ok(data.len())
+    }
+
+    // `whence` is 0 for the start of the file, 1 for the current position and 2 for the end
+    pub fn seek(&mut self, descriptor: u32, offset: i32, whence: u32) -> Result<u64, FsError>{
+        let file = self.files.get_mut(&descriptor).ok_or(FsError::BadDescriptor)?;
+
+        let position = match whence{
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(FsError::InvalidArgument),
+        };
+
+        Ok(file.seek(position)?)
+    }
+
+    // Forked VMs get their own descriptors for the same files. They share the file positions with the parent
+    pub fn fork(&self) -> Self{
+        FileTable{
+            root: self.root.clone(),
+            files: self.files.iter()
+                .filter_map(|(descriptor, file)| file.try_clone().ok().map(|file| (*descriptor, file)))
+                .collect(),
+            next_descriptor: self.next_descriptor,
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn dangling_links_are_refused(){
+        let root = std::env::temp_dir().join(format!("dbv-fs-{}", std::process::id()));
+        let outside = root.with_extension("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+
+        let mut files = FileTable::new();
+        files.set_root(&root).unwrap();
+        let result = files.open("escape", OPEN_WRITE | OPEN_CREATE);
+        let created = outside.exists();
+        let _ = std::fs::remove_dir_all(&root);
+        let _ = std::fs::remove_file(&outside);
+
+        assert_eq!(result, Err(FsError::PermissionDenied));
+        assert!(!created);
+    }
+}
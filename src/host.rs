@@ -0,0 +1,23 @@
+use std::sync::Arc;
+
+use crate::memory::Memory;
+use crate::registers::Registers;
+
+// Host Function Design:
+//
+// Embedders register Rust closures under a numeric id with `VirtualMachine::register_host_fn`.
+// Guest code calls them with HCALL - in immediate mode the id is the value (HCALL 0x10),
+// in register mode it's read from the register (HCALL R1).
+//
+// The closure gets a VmContext with the guest's registers and physical memory, and passes
+// results back by setting registers or writing memory. Returning an error stops the VM with
+// a HostFunction fault, holding the id, the error's message and the PC of the HCALL.
+
+pub struct VmContext<'a>{
+    pub registers: &'a mut Registers,
+    pub memory: &'a mut Memory,
+}
+
+pub type HostError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type HostFunction = Arc<dyn Fn(&mut VmContext) -> Result<(), HostError> + Send + Sync>;
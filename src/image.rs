@@ -0,0 +1,425 @@
+use std::path::Path;
+
+use crate::executable::{words, Executable};
+use crate::layout::HEAP_START;
+
+// Image Formats:
+//
+// Programs can be exchanged as:
+//
+// | Format | Extensions                   | Description                                                  |
+// | Dbv    | .dbv                         | Executable with a header, see executable.rs and signature.rs |
+// | Raw    | (--raw)                      | Headerless big endian instruction words                      |
+// | Hex    | .hex .ihex                   | Intel HEX records                                            |
+// | Srec   | .srec .s19 .s28 .s37 .mot    | Motorola S-records                                           |
+// | Words  | .words .mem                  | One hex instruction word per line (like Verilog's $readmemh) |
+//
+// Intel HEX and S-records hold bytes at addresses. The block of bytes starting at address 0 is
+// the text (big endian words, like a .dbv file). Every other record is data, placed at its address
+// in memory, with any gaps between records cleared to zero. The start address record (Intel HEX 03
+// or 05, S7/S8/S9) is the entry point, as a code address.
+//
+// Raw files and word lists only hold instructions, so they can't be written for programs with data,
+// bss or an entry point other than 0. Only .dbv files have a relocation table - programs written in
+// the other formats can only be loaded at code address 0.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFormat{
+    Dbv,
+    Raw,
+    Hex,
+    Srec,
+    Words,
+}
+
+impl ImageFormat{
+    pub fn from_name(name: &str) -> Option<Self>{
+        match name{
+            "dbv" => Some(ImageFormat::Dbv),
+            "raw" => Some(ImageFormat::Raw),
+            "hex" | "ihex" => Some(ImageFormat::Hex),
+            "srec" => Some(ImageFormat::Srec),
+            "words" => Some(ImageFormat::Words),
+            _ => None,
+        }
+    }
+
+    // Guess the format from a file's extension. Anything unknown is a .dbv executable
+    pub fn from_path<T>(path: &T) -> Self where T: AsRef<Path> + ?Sized{
+        let extension = path.as_ref().extension().and_then(|x| x.to_str()).unwrap_or("").to_ascii_lowercase();
+        match extension.as_str(){
+            "hex" | "ihex" => ImageFormat::Hex,
+            "srec" | "s19" | "s28" | "s37" | "mot" => ImageFormat::Srec,
+            "words" | "mem" => ImageFormat::Words,
+            _ => ImageFormat::Dbv,
+        }
+    }
+}
+
+// Read a program in any format
+pub fn read_image(bytes: &[u8], format: ImageFormat) -> Result<Executable, String>{
+    match format{
+        ImageFormat::Dbv => Executable::parse(bytes).map_err(String::from),
+        ImageFormat::Raw => Executable::from_raw(bytes).map_err(String::from),
+        ImageFormat::Hex => to_executable(parse_ihex(&text(bytes)?)?),
+        ImageFormat::Srec => to_executable(parse_srec(&text(bytes)?)?),
+        ImageFormat::Words => parse_words(&text(bytes)?),
+    }
+}
+
+// Write a program in any format
+pub fn write_image(executable: &Executable, format: ImageFormat) -> Result<Vec<u8>, String>{
+    match format{
+        ImageFormat::Dbv => Ok(executable.to_bytes()),
+        ImageFormat::Raw => {
+            only_instructions(executable, "Raw files")?;
+            Ok(executable.text.iter().flat_map(|x| x.to_be_bytes()).collect())
+        },
+        ImageFormat::Hex => Ok(write_ihex(&to_records(executable)?, executable.entry).into_bytes()),
+        ImageFormat::Srec => Ok(write_srec(&to_records(executable)?, executable.entry).into_bytes()),
+        ImageFormat::Words => {
+            only_instructions(executable, "Word lists")?;
+            Ok(executable.text.iter().map(|x| format!("{:08X}\n", x)).collect::<String>().into_bytes())
+        },
+    }
+}
+
+fn text(bytes: &[u8]) -> Result<String, String>{
+    String::from_utf8(bytes.to_vec()).map_err(|_| String::from("Image isn't a text file"))
+}
+
+fn only_instructions(executable: &Executable, format: &str) -> Result<(), String>{
+    if !executable.data.is_empty() || executable.bss_size != 0 || executable.entry != 0 || executable.text_address != 0{
+        return Err(format!("{} can only hold instructions, with the entry point at 0", format));
+    }
+    Ok(())
+}
+
+// Bytes at addresses, and the start address if there was one
+struct Records{
+    blocks: Vec<(u32, Vec<u8>)>,
+    entry: Option<u32>,
+}
+
+impl Records{
+    fn add(&mut self, address: u32, data: &[u8]){
+        // Most records continue the one before them
+        if let Some((start, block)) = self.blocks.last_mut(){
+            if *start as u64 + block.len() as u64 == address as u64{
+                block.extend_from_slice(data);
+                return;
+            }
+        }
+        self.blocks.push((address, data.to_vec()));
+    }
+}
+
+fn to_executable(mut records: Records) -> Result<Executable, String>{
+    records.blocks.sort_by_key(|x| x.0);
+
+    // Join touching blocks, and refuse overlapping ones
+    let mut blocks: Vec<(u32, Vec<u8>)> = Vec::new();
+    for (address, data) in records.blocks{
+        match blocks.last_mut(){
+            Some((start, block)) if (*start as u64 + block.len() as u64) > address as u64 => {
+                return Err(format!("Records overlap at 0x{:08X}", address));
+            },
+            Some((start, block)) if *start as u64 + block.len() as u64 == address as u64 => block.extend_from_slice(&data),
+            _ => blocks.push((address, data)),
+        }
+    }
+
+    let mut blocks = blocks.into_iter().peekable();
+    let text = match blocks.next_if(|x| x.0 == 0){
+        Some((_, text)) => words(&text)?,
+        None => return Err(String::from("Image has no instructions at address 0")),
+    };
+
+    // Everything else is one data section, with the gaps cleared. Check it fits before filling
+    // the gaps, so records far apart can't use up the host's memory
+    let mut data_address = 0;
+    let mut data = Vec::new();
+    for (address, block) in blocks{
+        if address as u64 + block.len() as u64 > HEAP_START as u64{
+            return Err(format!("Data at 0x{:08X} doesn't fit below the heap", address));
+        }
+        if data.is_empty(){
+            data_address = address;
+        }
+        data.resize((address - data_address) as usize, 0);
+        data.extend_from_slice(&block);
+    }
+
+    let executable = Executable{
+        entry: records.entry.unwrap_or(0),
+        text,
+        data_address,
+        data,
+        ..Default::default()
+    };
+
+    executable.validate()?;
+    Ok(executable)
+}
+
+fn to_records(executable: &Executable) -> Result<Vec<(u32, Vec<u8>)>, String>{
+    if executable.text_address != 0{
+        return Err(String::from("Text has to be at code address 0"));
+    }
+
+    let text: Vec<u8> = executable.text.iter().flat_map(|x| x.to_be_bytes()).collect();
+    let mut blocks = vec![(0, text)];
+
+    if !executable.data.is_empty(){
+        blocks.push((executable.data_address, executable.data.clone()));
+    }
+    if executable.bss_size != 0{
+        blocks.push((executable.bss_address, vec![0; executable.bss_size as usize]));
+    }
+
+    // Text is at address 0 in the image. Data right after it would be read back as more text
+    let text_end = executable.text.len() as u64 * 4;
+    for (address, _) in &blocks[1..]{
+        if (*address as u64) <= text_end{
+            return Err(format!("Data at 0x{:08X} has to start after the text, which ends at 0x{:08X} in the image", address, text_end));
+        }
+    }
+
+    Ok(blocks)
+}
+
+fn parse_hex_bytes(text: &str, line: usize) -> Result<Vec<u8>, String>{
+    if !text.len().is_multiple_of(2) || !text.is_ascii(){
+        return Err(format!("Invalid record on line {}", line));
+    }
+
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| format!("Invalid record on line {}", line)))
+        .collect()
+}
+
+fn parse_ihex(text: &str) -> Result<Records, String>{
+    let mut records = Records{blocks: Vec::new(), entry: None};
+    let mut base = 0u32; // From extended segment (02) and linear (04) address records
+
+    for (number, line) in text.lines().enumerate(){
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty(){
+            continue;
+        }
+
+        let bytes = match line.strip_prefix(':'){
+            Some(x) => parse_hex_bytes(x, number)?,
+            None => return Err(format!("Invalid record on line {}", number)),
+        };
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5{
+            return Err(format!("Invalid record length on line {}", number));
+        }
+        if bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0{
+            return Err(format!("Checksum mismatch on line {}", number));
+        }
+
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+        let value = data.iter().fold(0u32, |value, x| (value << 8) | *x as u32);
+
+        match bytes[3]{
+            0x00 => records.add(base.wrapping_add(address), data),
+            0x01 => break,
+            0x02 if data.len() == 2 => base = value << 4,
+            0x03 if data.len() == 4 => records.entry = Some(((value >> 16) << 4) + (value & 0xFFFF)),
+            0x04 if data.len() == 2 => base = value << 16,
+            0x05 if data.len() == 4 => records.entry = Some(value),
+            _ => return Err(format!("Unsupported record on line {}", number)),
+        }
+    }
+
+    Ok(records)
+}
+
+fn write_ihex(blocks: &[(u32, Vec<u8>)], entry: u32) -> String{
+    let record = |kind: u8, address: u16, data: &[u8]| -> String{
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)).wrapping_neg();
+        bytes.push(checksum);
+
+        format!(":{}\n", bytes.iter().map(|x| format!("{:02X}", x)).collect::<String>())
+    };
+
+    let mut text = String::new();
+    let mut upper = 0u32;
+    for (address, block) in blocks{
+        for (i, chunk) in block.chunks(16).enumerate(){
+            let address = address + i as u32 * 16;
+
+            // Records can't cross a 64KiB boundary, so split any that would
+            let split = (0x10000 - (address & 0xFFFF) as usize).min(chunk.len());
+            for (address, chunk) in [(address, &chunk[..split]), (address + split as u32, &chunk[split..])]{
+                if chunk.is_empty(){
+                    continue;
+                }
+                if address >> 16 != upper{
+                    upper = address >> 16;
+                    text += &record(0x04, 0, &(upper as u16).to_be_bytes());
+                }
+                text += &record(0x00, address as u16, chunk);
+            }
+        }
+    }
+
+    text += &record(0x05, 0, &entry.to_be_bytes());
+    text += &record(0x01, 0, &[]);
+    text
+}
+
+fn parse_srec(text: &str) -> Result<Records, String>{
+    let mut records = Records{blocks: Vec::new(), entry: None};
+
+    for (number, line) in text.lines().enumerate(){
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty(){
+            continue;
+        }
+
+        if line.len() < 4 || !line.is_ascii() || !line.starts_with('S'){
+            return Err(format!("Invalid record on line {}", number));
+        }
+        let kind = &line[1..2];
+        let bytes = parse_hex_bytes(&line[2..], number)?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1{
+            return Err(format!("Invalid record length on line {}", number));
+        }
+        if bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0xFF{
+            return Err(format!("Checksum mismatch on line {}", number));
+        }
+
+        let address_size = match kind{
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(format!("Unsupported record on line {}", number)),
+        };
+        if bytes.len() < address_size + 2{
+            return Err(format!("Invalid record length on line {}", number));
+        }
+
+        let address = bytes[1..=address_size].iter().fold(0u32, |value, x| (value << 8) | *x as u32);
+        let data = &bytes[address_size + 1..bytes.len() - 1];
+
+        match kind{
+            "1" | "2" | "3" => records.add(address, data),
+            "7" | "8" | "9" => records.entry = Some(address),
+            _ => {}, // Header and record counts
+        }
+    }
+
+    Ok(records)
+}
+
+fn write_srec(blocks: &[(u32, Vec<u8>)], entry: u32) -> String{
+    // S0 has a 16-bit address, S3 and S7 have 32-bit addresses
+    let record = |kind: u8, address: u32, data: &[u8]| -> String{
+        let address = if kind == 0 { &address.to_be_bytes()[2..] } else { &address.to_be_bytes()[..] };
+
+        let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(data);
+        let checksum = !bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x));
+        bytes.push(checksum);
+
+        format!("S{}{}\n", kind, bytes.iter().map(|x| format!("{:02X}", x)).collect::<String>())
+    };
+
+    // S0 header, S3 data, S7 start address
+    let mut text = record(0, 0, b"dbv");
+    for (address, block) in blocks{
+        for (i, chunk) in block.chunks(16).enumerate(){
+            text += &record(3, address + i as u32 * 16, chunk);
+        }
+    }
+    text += &record(7, entry, &[]);
+    text
+}
+
+fn parse_words(text: &str) -> Result<Executable, String>{
+    let mut program = Vec::new();
+
+    for (number, line) in text.lines().enumerate(){
+        // Comments start with # or //
+        let line = line.split('#').next().unwrap_or("").split("//").next().unwrap_or("").trim();
+        for word in line.split_whitespace(){
+            let word = word.strip_prefix("0x").unwrap_or(word).replace('_', "");
+            match u32::from_str_radix(&word, 16){
+                Ok(x) => program.push(x),
+                Err(_) => return Err(format!("Invalid word on line {}", number + 1)),
+            }
+        }
+    }
+
+    let executable = Executable{
+        text: program,
+        ..Default::default()
+    };
+
+    executable.validate()?;
+    Ok(executable)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // Data crosses a 64KiB boundary, so Intel HEX needs more than one upper address record
+    fn executable() -> Executable{
+        Executable{
+            entry: 4,
+            text: vec![0x1F400001, 4, 0x00000000], // JMP 4, HLT
+            data_address: 0xFFF8,
+            data: (1..=20).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hex_and_srec_round_trip(){
+        for format in [ImageFormat::Hex, ImageFormat::Srec]{
+            let bytes = write_image(&executable(), format).unwrap();
+            assert_eq!(read_image(&bytes, format), Ok(executable()), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn bss_is_written_as_zeros(){
+        let executable = Executable{bss_address: 0x10010, bss_size: 8, ..executable()};
+        let bytes = write_image(&executable, ImageFormat::Srec).unwrap();
+
+        let mut data = executable.data.clone();
+        data.resize(0x20, 0);
+        assert_eq!(read_image(&bytes, ImageFormat::Srec).map(|x| x.data), Ok(data));
+    }
+
+    #[test]
+    fn damaged_records_are_refused(){
+        for format in [ImageFormat::Hex, ImageFormat::Srec]{
+            let mut text = String::from_utf8(write_image(&executable(), format).unwrap()).unwrap();
+            // Change a data byte in the first line, without fixing its checksum
+            let digit = if format == ImageFormat::Hex { 10 } else { 13 };
+            let replacement = if &text[digit..digit + 1] == "F" { "E" } else { "F" };
+            text.replace_range(digit..digit + 1, replacement);
+
+            assert_eq!(read_image(text.as_bytes(), format), Err(String::from("Checksum mismatch on line 1")), "{:?}", format);
+        }
+
+        assert_eq!(read_image("S\u{E9}0000\n".as_bytes(), ImageFormat::Srec), Err(String::from("Invalid record on line 1")));
+    }
+
+    #[test]
+    fn data_has_to_fit_below_the_heap(){
+        // Joining these would need 4GiB
+        let records = Records{blocks: vec![(0, vec![0; 4]), (0x100, vec![1; 4]), (0xFFFFFF00, vec![2; 4])], entry: None};
+        assert_eq!(to_executable(records).map(|_| ()), Err(String::from("Data at 0xFFFFFF00 doesn't fit below the heap")));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{enum_conv_gen, utils::Parameter, registers::Registers, memory::Memory};
+use crate::enum_conv_gen;
 
 // Instruction Design:
 //
@@ -16,11 +16,16 @@ use crate::{enum_conv_gen, utils::Parameter, registers::Registers, memory::Memor
 // It's then up to the instruction to parse the arguments
 // and do what it needs to do
 
+// Flag (low 4 bits, Register mode) asking HLT to exit with the destination register's value,
+// eg: `HLT R5`. A plain HLT (0x00000000) exits with 0
+pub const HLT_STATUS_REGISTER: u32 = 0x1;
+
 
 enum_conv_gen! {
+    #[allow(clippy::upper_case_acronyms)]
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub enum Instructions {
-        HLT = 0x0,// Halts the program
+        HLT = 0x0,// Halts the program, with exit status 0, an immediate or a register (see HLT_STATUS_REGISTER)
         
         PSH,      // Push val to stack. Depending on the mode, val can be a register or a value
         POP,      // Remove from stack and depending on the mode, store in a register or discard
@@ -69,6 +74,21 @@ enum_conv_gen! {
         CALL,     // Call a function at addr (Must be followed by RET, or undefined behavior)
         
         RET,      // Return from a jump
+
+        // System operations
+        MTCR,     // Move a register into a control register (MTCR CR, R)
+        MFCR,     // Move a control register into a register (MFCR R, CR)
+        TLBF,     // Flush the MMU's TLB - must be done after changing a mapped page table entry
+        SYS,      // System call - the call number is in R0, see syscall.rs
+
+        // Interrupts - see interrupts.rs
+        EI,       // Enable interrupts
+        DI,       // Disable interrupts
+        IRET,     // Return from an interrupt or exception handler, restoring the flags and PC
+
+        HCALL,    // Call a function registered by the embedder, see host.rs
+
+        UD = 0xFF, // Undefined instruction - unknown opcodes decode to this, and it raises an invalid opcode exception
     }
 }
 
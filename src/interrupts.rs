@@ -0,0 +1,43 @@
+// Interrupt Design:
+//
+// The IVT control register holds the physical address of the interrupt vector table.
+// The table is an array of 32-bit little endian handler addresses (byte addresses in the
+// program), indexed by vector number. A handler address of 0 means there's no handler, and an
+// IVT of 0 means there's no table.
+//
+// | Vector | Cause                                                 |
+// | 0x00   | Invalid opcode                                        |
+// | 0x01   | Divide by zero                                        |
+// | 0x02   | Page fault (FAR holds the faulting address)           |
+// | 0x03   | Misaligned access (FAR holds the faulting address)    |
+// | 0x04   | Bus error - access outside of memory (FAR as above),  |
+// |        | or running outside of the program (FAR is the PC)     |
+// | 0x05   | Reserved                                              |
+// | 0x06   | Reserved                                              |
+// | 0x07   | Reserved                                              |
+// | 0x08+n | Interrupt line n (0 - 15)                             |
+//
+// Exceptions are raised by the instruction that caused them, and are taken even when
+// interrupts are disabled. If there's no handler, the exception stops the VM.
+// The saved PC is the faulting instruction, so returning restarts it.
+//
+// Interrupt lines are taken between instructions when interrupts are enabled (EI).
+// Lower numbered lines have priority. A line with no handler stays pending.
+//
+// On entry to a handler, the VM pushes the PC and then the flags (see Registers::get_flags)
+// to the stack, and disables interrupts. IRET pops them back.
+
+pub const INTERRUPT_ENABLE: u8 = 0x1; // Bit of the interrupt flag
+
+pub const VECTOR_INVALID_OPCODE: u32 = 0x00;
+pub const VECTOR_DIVIDE_BY_ZERO: u32 = 0x01;
+pub const VECTOR_PAGE_FAULT: u32 = 0x02;
+pub const VECTOR_MISALIGNED: u32 = 0x03;
+pub const VECTOR_BUS_ERROR: u32 = 0x04;
+pub const VECTOR_IRQ_BASE: u32 = 0x08;
+
+pub const IRQ_LINES: u32 = 16;
+
+// Interrupt lines of the built in devices
+pub const IRQ_TIMER: u32 = 0;
+pub const IRQ_DISK: u32 = 1;
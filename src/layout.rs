@@ -0,0 +1,38 @@
+// Memory Layout:
+//
+// | 0x000000 - 0x07FFFF | Static data (eg: the 0x2000 region dump prints)
+// | 0x080000 - 0x0FFFFF | Framebuffer pixels, when the framebuffer device is attached (otherwise static data)
+// | 0x100000 - brk      | Heap - grows up from HEAP_START, moved with the BRK/SBRK system calls
+// | brk      - 0xFDFFFF | Free
+// | 0xFE0000 - 0xFEFFFF | Stack - grows down from STACK_TOP
+// | 0xFF0000 - 0xFFFFFE | Memory mapped devices
+//
+// The heap can't grow into the stack region; asking for a break past STACK_LIMIT
+// raises a HeapOverflow fault
+//
+// Program arguments and the environment are copied to the top of the stack before the program
+// starts, like a C runtime's entry (see VirtualMachine::set_arguments):
+//
+// | STACK_TOP - n ... STACK_TOP | The NUL terminated strings, padded to a multiple of 4 bytes
+// | envp                        | Pointers to the NAME=VALUE strings, then 0
+// | argv = SP                   | Pointers to the argument strings (argv[0] is the program), then 0
+//
+// The program starts with R1 = argc, R2 = argv and R3 = envp
+
+pub const FRAMEBUFFER_START: u32 = 0x080000;
+pub const FRAMEBUFFER_SIZE: u32 = 0x080000; // Enough for 640x400 RGB565
+
+pub const HEAP_START: u32 = 0x100000;
+
+pub const STACK_TOP: u32 = 0xFF0000;
+pub const STACK_SIZE: u32 = 0x10000;
+pub const STACK_LIMIT: u32 = STACK_TOP - STACK_SIZE; // Lowest address of the stack region
+pub const ARGUMENTS_SIZE: u32 = STACK_SIZE / 4; // Most of the stack the arguments and environment can take
+
+// Device base addresses - see devices/
+pub const MMIO_START: u32 = 0xFF0000;
+pub const UART_BASE: u32 = MMIO_START; // 2 bytes
+pub const TIMER_BASE: u32 = MMIO_START + 0x10; // 16 bytes
+pub const DISK_BASE: u32 = MMIO_START + 0x20; // 24 bytes
+pub const FRAMEBUFFER_BASE: u32 = MMIO_START + 0x40; // 28 bytes
+pub const RNG_BASE: u32 = MMIO_START + 0x60; // 4 bytes
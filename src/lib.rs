@@ -0,0 +1,21 @@
+pub mod cfg;
+pub mod debug;
+pub mod devices;
+pub mod executable;
+pub mod fault;
+pub mod fs;
+pub mod host;
+pub mod image;
+pub mod instructions;
+pub mod interrupts;
+pub mod layout;
+pub mod linker;
+pub mod memory;
+pub mod mmu;
+pub mod object;
+pub mod registers;
+pub mod signature;
+pub mod syscall;
+pub mod utils;
+pub mod verify;
+pub mod vm;
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::debug::DebugInfo;
+use crate::executable::{self, Executable};
+use crate::layout::HEAP_START;
+use crate::utils::code_addresses;
+use crate::object::{Binding, Object, RelocationKind, Section};
+
+// Linker Design:
+//
+// Objects are placed in the order they're given. Text is joined starting at code address 0
+// (code addresses count instructions, see executable.rs),
+// data is joined starting at the data address, and bss follows the data. Each object's data
+// and bss start on a 4 byte boundary.
+//
+// Every global symbol must be defined once. Relocations are resolved against the object's own
+// symbols first (local, or global and defined there), then the global symbols of every object.
+//
+// The entry point is the `_start` symbol (DEFAULT_ENTRY_SYMBOL, or --entry) if it's defined,
+// otherwise code address 0.
+//
+// Every absolute relocation is copied into the executable's relocation table, so the program can
+// be loaded at any base (see executable.rs). Relative ones are resolved here for good.
+
+pub const DEFAULT_DATA_ADDRESS: u32 = 0x2000;
+pub const DEFAULT_ENTRY_SYMBOL: &str = "_start";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError{
+    DuplicateSymbol{name: String, first: String, second: String},
+    UndefinedSymbol{name: String, object: String},
+    UndefinedEntry{name: String},
+    Layout(&'static str),
+}
+
+impl fmt::Display for LinkError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            LinkError::DuplicateSymbol{name, first, second} => write!(f, "Duplicate symbol `{}`, defined in {} and {}", name, first, second),
+            LinkError::UndefinedSymbol{name, object} => write!(f, "Undefined symbol `{}`, referenced from {}", name, object),
+            LinkError::UndefinedEntry{name} => write!(f, "Entry point `{}` isn't a global text symbol", name),
+            LinkError::Layout(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub struct Linker{
+    objects: Vec<(String, Object)>, // Name (for errors) and object
+    data_address: u32,
+    entry: String,
+    entry_required: bool, // Only the default entry point is optional
+}
+
+impl Default for Linker{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl Linker{
+    pub fn new() -> Self{
+        Linker{
+            objects: Vec::new(),
+            data_address: DEFAULT_DATA_ADDRESS,
+            entry: String::from(DEFAULT_ENTRY_SYMBOL),
+            entry_required: false,
+        }
+    }
+
+    pub fn add_object(&mut self, name: &str, object: Object){
+        self.objects.push((String::from(name), object));
+    }
+
+    pub fn set_data_address(&mut self, address: u32){
+        self.data_address = address;
+    }
+
+    pub fn set_entry(&mut self, symbol: &str){
+        self.entry = String::from(symbol);
+        self.entry_required = true;
+    }
+
+    // Link the objects into an executable, returning every error found
+    pub fn link(&self) -> Result<Executable, Vec<LinkError>>{
+        self.link_with_debug_info().map(|(executable, _)| executable)
+    }
+
+    // Link, and describe every symbol (local ones too) for the debugger - see debug.rs
+    pub fn link_with_debug_info(&self) -> Result<(Executable, DebugInfo), Vec<LinkError>>{
+        let mut errors = Vec::new();
+
+        if self.data_address >= HEAP_START{
+            return Err(vec![LinkError::Layout("Data and BSS must fit below the heap")]);
+        }
+
+        // Place the sections of each object. Sizes come from the object files, so they can add up to more than 4GiB
+        let too_big = || vec![LinkError::Layout("Sections don't fit in memory")];
+        let mut text_bases = Vec::new();
+        let mut text_addresses = Vec::new(); // Code address of each word in each object
+        let mut data_bases = Vec::new();
+        let mut text_size = 0u32;
+        let mut data_size = 0u32;
+        for (_, object) in &self.objects{
+            // Symbols can point at the end of the text too
+            let mut addresses = code_addresses(&object.text);
+            let size = addresses.iter().flatten().count() as u32 * 4;
+            addresses.push(Some(size));
+
+            text_bases.push(text_size);
+            text_size = text_size.checked_add(size).ok_or_else(too_big)?;
+            text_addresses.push(addresses);
+
+            data_size = data_size.checked_next_multiple_of(4).ok_or_else(too_big)?;
+            data_bases.push(self.data_address.checked_add(data_size).ok_or_else(too_big)?);
+            data_size = data_size.checked_add(object.data.len() as u32).ok_or_else(too_big)?;
+        }
+
+        let bss_address = self.data_address.checked_add(data_size).and_then(|x| x.checked_next_multiple_of(4)).ok_or_else(too_big)?;
+        let mut bss_bases = Vec::new();
+        let mut bss_size = 0u32;
+        for (_, object) in &self.objects{
+            bss_size = bss_size.checked_next_multiple_of(4).ok_or_else(too_big)?;
+            bss_bases.push(bss_address.checked_add(bss_size).ok_or_else(too_big)?);
+            bss_size = bss_size.checked_add(object.bss_size).ok_or_else(too_big)?;
+        }
+
+        let address_of = |index: usize, section: Section, value: u32| -> u32{
+            match section{
+                // Objects have checked their text symbols start an instruction
+                Section::Text => text_bases[index] + text_addresses[index][value as usize / 4].unwrap_or(0),
+                Section::Data => data_bases[index] + value,
+                Section::Bss => bss_bases[index] + value,
+                Section::Undefined => 0,
+            }
+        };
+
+        // Collect the global symbols
+        let mut globals: HashMap<&str, (usize, Section, u32)> = HashMap::new(); // Name -> (object, section, address)
+        for (index, (name, object)) in self.objects.iter().enumerate(){
+            for symbol in &object.symbols{
+                if symbol.binding != Binding::Global || symbol.section == Section::Undefined{
+                    continue;
+                }
+
+                let address = address_of(index, symbol.section, symbol.value);
+                if let Some((first, _, _)) = globals.insert(&symbol.name, (index, symbol.section, address)){
+                    errors.push(LinkError::DuplicateSymbol{
+                        name: symbol.name.clone(),
+                        first: self.objects[first].0.clone(),
+                        second: name.clone(),
+                    });
+                }
+            }
+        }
+
+        let mut debug_info = DebugInfo::new();
+        for (index, (_, object)) in self.objects.iter().enumerate(){
+            for symbol in &object.symbols{
+                let address = address_of(index, symbol.section, symbol.value);
+                match symbol.section{
+                    Section::Text => debug_info.add_text_symbol(address, &symbol.name),
+                    Section::Data | Section::Bss => debug_info.add_data_symbol(address, &symbol.name),
+                    Section::Undefined => {},
+                }
+            }
+        }
+
+        // Join the sections and apply the relocations
+        let mut text = Vec::new();
+        let mut data = Vec::new();
+        let mut load_relocations = Vec::new();
+        for (index, (name, object)) in self.objects.iter().enumerate(){
+            let text_start = text.len();
+            text.extend_from_slice(&object.text);
+
+            data.resize((data_bases[index] - self.data_address) as usize, 0);
+            let data_start = data.len();
+            data.extend_from_slice(&object.data);
+
+            for relocation in &object.relocations{
+                let symbol = &object.symbols[relocation.symbol as usize];
+                let address = match symbol.section{
+                    Section::Undefined => match globals.get(symbol.name.as_str()){
+                        Some((_, _, address)) => *address,
+                        None => {
+                            errors.push(LinkError::UndefinedSymbol{name: symbol.name.clone(), object: name.clone()});
+                            continue;
+                        }
+                    },
+                    section => address_of(index, section, symbol.value),
+                };
+
+                let value = match relocation.kind{
+                    RelocationKind::Absolute => address,
+                    RelocationKind::Relative => {
+                        let defined_in = match symbol.section{
+                            Section::Undefined => globals.get(symbol.name.as_str()).map(|x| x.1),
+                            section => Some(section),
+                        };
+                        if defined_in != Some(Section::Text){
+                            errors.push(LinkError::Layout("Relative relocations can only refer to text symbols"));
+                            continue;
+                        }
+
+                        // Objects have checked the word is an extension, so the jump is the word before it
+                        let jump = text_bases[index] + text_addresses[index][relocation.offset as usize / 4 - 1].unwrap_or(0);
+                        address.wrapping_sub(jump)
+                    },
+                };
+
+                match relocation.section{
+                    Section::Text => {
+                        let offset = text_start + relocation.offset as usize / 4;
+                        text[offset] = text[offset].wrapping_add(value);
+                        if relocation.kind == RelocationKind::Absolute{
+                            load_relocations.push(executable::Relocation{section: Section::Text, offset: offset as u32 * 4});
+                        }
+                    },
+                    _ => {
+                        let offset = data_start + relocation.offset as usize;
+                        load_relocations.push(executable::Relocation{section: Section::Data, offset: offset as u32});
+                        let word = u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+                        data[offset..offset + 4].copy_from_slice(&word.wrapping_add(value).to_le_bytes());
+                    },
+                }
+            }
+        }
+
+        // The entry point has to be code
+        let entry = match globals.get(self.entry.as_str()){
+            Some((_, Section::Text, address)) => *address,
+            _ if self.entry_required => {
+                errors.push(LinkError::UndefinedEntry{name: self.entry.clone()});
+                0
+            },
+            _ => 0,
+        };
+
+        if !errors.is_empty(){
+            return Err(errors);
+        }
+
+        let executable = Executable{
+            entry,
+
+            text_address: 0,
+            text,
+
+            data_address: self.data_address,
+            data,
+
+            bss_address,
+            bss_size,
+
+            relocations: Some(load_relocations),
+        };
+
+        executable.validate().map_err(|e| vec![LinkError::Layout(e)])?;
+        Ok((executable, debug_info))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::object::{Relocation, Symbol};
+    use crate::vm::VirtualMachine;
+
+    fn symbol(name: &str, section: Section, value: u32) -> Symbol{
+        Symbol{name: String::from(name), section, value, binding: Binding::Global}
+    }
+
+    // `_start` jumps to `done` in another object, which halts with the word at `status`
+    fn objects() -> (Object, Object){
+        let start = Object{
+            text: vec![0x1F400001, 0], // JMP done
+            symbols: vec![symbol("_start", Section::Text, 0), symbol("done", Section::Undefined, 0)],
+            relocations: vec![Relocation{section: Section::Text, offset: 4, symbol: 1, kind: RelocationKind::Absolute}],
+            ..Default::default()
+        };
+        let done = Object{
+            text: vec![0x03401001, 0, 0x11000100, 0x00000001], // SET R1, status; LD R0, [R1]; HLT R0
+            data: vec![0x2A, 0, 0, 0],
+            symbols: vec![symbol("done", Section::Text, 0), symbol("status", Section::Data, 0)],
+            relocations: vec![Relocation{section: Section::Text, offset: 4, symbol: 1, kind: RelocationKind::Absolute}],
+            ..Default::default()
+        };
+        (start, done)
+    }
+
+    #[test]
+    fn linked_objects_run(){
+        let (start, done) = objects();
+        let mut linker = Linker::new();
+        linker.add_object("done.o", done);
+        linker.add_object("start.o", start);
+        let executable = linker.link().unwrap();
+
+        // `done` comes first, so `_start` is the entry point rather than code address 0
+        assert_eq!(executable.entry, 12);
+        assert_eq!(executable.text[1], DEFAULT_DATA_ADDRESS);
+
+        let mut virtual_machine = VirtualMachine::new();
+        virtual_machine.load_executable(&executable).unwrap();
+        assert_eq!(virtual_machine.run(), Ok(0x2A));
+    }
+
+    #[test]
+    fn sections_bigger_than_memory_are_refused(){
+        let mut linker = Linker::new();
+        for name in ["a.o", "b.o"]{
+            linker.add_object(name, Object{text: vec![0x00000000], bss_size: 0x80000000, ..Default::default()});
+        }
+
+        assert_eq!(linker.link(), Err(vec![LinkError::Layout("Sections don't fit in memory")]));
+    }
+
+    #[test]
+    fn undefined_symbols_are_reported(){
+        let (start, _) = objects();
+        let mut linker = Linker::new();
+        linker.add_object("start.o", start);
+
+        assert_eq!(linker.link(), Err(vec![LinkError::UndefinedSymbol{name: String::from("done"), object: String::from("start.o")}]));
+    }
+}
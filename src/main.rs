@@ -1,26 +1,609 @@
-mod instructions;
-mod memory;
-mod registers;
-mod utils;
-mod vm;
-
-use instructions::*;
-use memory::*;
-use registers::*;
-use utils::*;
-use vm::*;
+use dbv_rs_new::{interrupts, layout};
+use dbv_rs_new::vm::VirtualMachine;
+use dbv_rs_new::debug::DebugInfo;
+use dbv_rs_new::fault::Fault;
+use dbv_rs_new::image::{read_image, write_image, ImageFormat};
+use dbv_rs_new::verify::verify;
+use dbv_rs_new::linker::Linker;
+use dbv_rs_new::object::Object;
+use dbv_rs_new::signature::{self, TrustedKeys};
+use dbv_rs_new::devices::disk::{Disk, DiskMode};
+use dbv_rs_new::devices::framebuffer::{FrameOutput, Framebuffer, FRAMEBUFFER_FORMAT_PALETTE, FRAMEBUFFER_FORMAT_RGB565};
+use dbv_rs_new::devices::uart::Uart;
+
+// Exit code of `dbv run` when the VM stops with a fault, or the program can't be loaded
+const ERROR_EXIT_CODE: i32 = 1;
+
+fn usage() -> ! {
+    eprintln!("Usage: dbv [run] [program.dbv] [--uart-input <file>] [--disk <image>] [--disk-mode rw|ro|overlay]");
+    eprintln!("           [--frames <directory|file.ppm>] [--frame-format ppm|png] [--framebuffer <width>x<height>] [--framebuffer-format palette|rgb565]");
+    eprintln!("           [--seed <n>] [--fs-root <directory>] [--raw] [--debug-info <file.dbg>] [--trace]");
+    eprintln!("           [--load <file>@<address>]... [--dump-mem <address>:<length>=<file>]... [--env <name>=<value>]...");
+    eprintln!("           [--base <address>] [--library <file>@<address>]... [--trusted-keys <file>] [-- <argument>...]");
+    eprintln!("       dbv link <object.o>... -o <program.dbv> [--entry <symbol>] [--data-address <address>] [-g]");
+    eprintln!("       dbv verify <program.dbv> [--raw]");
+    eprintln!("       dbv convert <input> <output> [--from dbv|raw|hex|srec|words] [--to dbv|raw|hex|srec|words]");
+    eprintln!("       dbv sign <program.dbv> [--key <key file>] [-o <output.dbv>]");
+    eprintln!("       dbv keygen <key file> [--force]");
+    eprintln!();
+    eprintln!("Programs can also be Intel HEX (.hex), S-records (.srec, .s19, .s28, .s37, .mot) or word lists (.words, .mem)");
+    eprintln!();
+    eprintln!("`run` exits with the program's exit status (255 if it's bigger), or {} if it stopped with a fault", ERROR_EXIT_CODE);
+    std::process::exit(ERROR_EXIT_CODE);
+}
 
 fn main() {  
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // `run` is the default command
+    let code = match args.first().map(String::as_str){
+        Some("run") => run(args[1..].to_vec()),
+        Some("link") => link(args[1..].to_vec()),
+        Some("verify") => verify_program(args[1..].to_vec()),
+        Some("convert") => convert(args[1..].to_vec()),
+        Some("sign") => sign(args[1..].to_vec()),
+        Some("keygen") => keygen(args[1..].to_vec()),
+        _ => run(args),
+    };
+
+    std::process::exit(code);
+}
+
+fn run(args: Vec<String>) -> i32{
+    let mut program_path = String::from("main.dbv");
+    let mut uart_input: Option<String> = None;
+    let mut disk_image: Option<String> = None;
+    let mut disk_mode = DiskMode::ReadWrite;
+    let mut frames: Option<String> = None;
+    let mut frame_format = String::from("ppm");
+    let mut framebuffer_size = (320, 200);
+    let mut framebuffer_format = FRAMEBUFFER_FORMAT_PALETTE;
+    let mut seed: Option<u64> = None;
+    let mut fs_root: Option<String> = None;
+    let mut raw = false;
+    let mut debug_info: Option<String> = None;
+    let mut trace = false;
+    let mut loads: Vec<(String, u32)> = Vec::new();
+    let mut dumps: Vec<(u32, u32, String)> = Vec::new();
+    let mut environment: Vec<String> = Vec::new();
+    let mut base = 0;
+    let mut libraries: Vec<(String, u32)> = Vec::new();
+    let mut trusted_keys: Option<String> = None;
+    let mut program_arguments: Vec<String> = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next(){
+        match arg.as_str(){
+            "--uart-input" => uart_input = Some(args.next().unwrap_or_else(|| usage())),
+            "--disk" => disk_image = Some(args.next().unwrap_or_else(|| usage())),
+            "--disk-mode" => disk_mode = match args.next().as_deref(){
+                Some("rw") => DiskMode::ReadWrite,
+                Some("ro") => DiskMode::ReadOnly,
+                Some("overlay") => DiskMode::Overlay,
+                _ => usage(),
+            },
+            "--frames" => frames = Some(args.next().unwrap_or_else(|| usage())),
+            "--frame-format" => frame_format = args.next().unwrap_or_else(|| usage()),
+            "--framebuffer" => {
+                let size = args.next().unwrap_or_else(|| usage());
+                framebuffer_size = match size.split_once('x').map(|(w, h)| (w.parse(), h.parse())){
+                    Some((Ok(width), Ok(height))) => (width, height),
+                    _ => usage(),
+                };
+            },
+            "--seed" => seed = match args.next().map(|x| x.parse()){
+                Some(Ok(x)) => Some(x),
+                _ => usage(),
+            },
+            "--raw" => raw = true,
+            "--debug-info" => debug_info = Some(args.next().unwrap_or_else(|| usage())),
+            "--trace" => trace = true,
+            "--env" => {
+                let variable = args.next().unwrap_or_else(|| usage());
+                if !variable.contains('='){
+                    usage();
+                }
+                environment.push(variable);
+            },
+            // Everything after -- is for the program
+            "--" => {
+                program_arguments.extend(args.by_ref());
+                break;
+            },
+            "--load" => loads.push(parse_placement(&args.next().unwrap_or_else(|| usage()))),
+            "--base" => base = args.next().as_deref().and_then(parse_number).unwrap_or_else(|| usage()),
+            "--library" => libraries.push(parse_placement(&args.next().unwrap_or_else(|| usage()))),
+            "--trusted-keys" => trusted_keys = Some(args.next().unwrap_or_else(|| usage())),
+            "--dump-mem" => {
+                // address:length=file
+                let spec = args.next().unwrap_or_else(|| usage());
+                let range = spec.split_once('=').and_then(|(range, path)| Some((range.split_once(':')?, path)));
+                dumps.push(match range.map(|((address, length), path)| (parse_number(address), parse_number(length), path)){
+                    Some((Some(address), Some(length), path)) if !path.is_empty() => (address, length, String::from(path)),
+                    _ => usage(),
+                });
+            },
+            "--fs-root" => fs_root = Some(args.next().unwrap_or_else(|| usage())),
+            "--framebuffer-format" => framebuffer_format = match args.next().as_deref(){
+                Some("palette") => FRAMEBUFFER_FORMAT_PALETTE,
+                Some("rgb565") => FRAMEBUFFER_FORMAT_RGB565,
+                _ => usage(),
+            },
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage();
+            },
+            _ => program_path = arg,
+        }
+    }
+
     let mut virtual_machine = VirtualMachine::new();
 
-    // Load the program
-    virtual_machine.load_program("main.dbv").unwrap();
+    // Without a seed, pick one from the clock. It's printed in the dump, so the run can be replayed with --seed
+    let seed = seed.unwrap_or_else(|| {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|x| x.as_nanos() as u64).unwrap_or(0)
+    });
+    virtual_machine.seed_rng(seed);
+
+    // Without a root, the guest can't open any files
+    if let Some(path) = fs_root{
+        if let Err(e) = virtual_machine.files.set_root(&path){
+            eprintln!("Couldn't use {} as the filesystem root: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    // Feed the UART from a file instead of stdin
+    if let Some(path) = uart_input{
+        let uart = Uart::from_file(&path).unwrap_or_else(|e| {
+            eprintln!("Couldn't read UART input {}: {}", path, e);
+            std::process::exit(1);
+        });
+        virtual_machine.devices.attach(layout::UART_BASE, Box::new(uart));
+    }
+
+    if let Some(path) = disk_image{
+        let disk = Disk::open(&path, disk_mode).unwrap_or_else(|e| {
+            eprintln!("Couldn't open disk image {}: {}", path, e);
+            std::process::exit(1);
+        });
+        virtual_machine.devices.attach_with_irq(layout::DISK_BASE, interrupts::IRQ_DISK, Box::new(disk));
+    }
+
+    // The framebuffer is only attached when there's somewhere to write frames
+    if let Some(path) = frames{
+        let output = if path.ends_with(".ppm"){
+            FrameOutput::PpmStream(path.into())
+        }else{
+            if let Err(e) = std::fs::create_dir_all(&path){
+                eprintln!("Couldn't create frame directory {}: {}", path, e);
+                std::process::exit(1);
+            }
+
+            match frame_format.as_str(){
+                "ppm" => FrameOutput::Ppm(path.into()),
+                "png" => FrameOutput::Png(path.into()),
+                _ => usage(),
+            }
+        };
+
+        let (width, height) = framebuffer_size;
+        let framebuffer = Framebuffer::new(output, layout::FRAMEBUFFER_START, width, height, framebuffer_format);
+        virtual_machine.devices.attach(layout::FRAMEBUFFER_BASE, Box::new(framebuffer));
+    }
+
+    // With trusted keys, every image has to be signed by one of them
+    if let Some(path) = trusted_keys{
+        match TrustedKeys::load(&path){
+            Ok(x) => virtual_machine.set_trusted_keys(x),
+            Err(e) => {
+                eprintln!("Couldn't load trusted keys {}: {}", path, e);
+                return ERROR_EXIT_CODE;
+            }
+        }
+    }
+
+    // Load the program. Headerless programs have to be asked for, so garbage isn't run as code.
+    // Other formats are recognised by their extension
+    let format = if raw { ImageFormat::Raw } else { ImageFormat::from_path(&program_path) };
+    if let Err(e) = virtual_machine.load_image_at(&program_path, format, base){
+        eprintln!("Couldn't load {}: {}", program_path, e);
+        return ERROR_EXIT_CODE;
+    }
+
+    // Libraries are placed around the program, at the addresses it expects them
+    for (path, address) in &libraries{
+        if let Err(e) = virtual_machine.load_library_image(path, ImageFormat::from_path(path), *address){
+            eprintln!("Couldn't load library {}: {}", path, e);
+            return ERROR_EXIT_CODE;
+        }
+    }
 
-    match virtual_machine.run(){
-        Ok(_) => println!("Program exited successfully"),
-        Err(e) => println!("Program exited with error: {:?}", e),
+    // argv[0] is the program, like on a host
+    program_arguments.insert(0, program_path.clone());
+    if let Err(e) = virtual_machine.set_arguments(&program_arguments, &environment){
+        eprintln!("Couldn't pass the arguments to {}: {}", program_path, e);
+        return ERROR_EXIT_CODE;
     }
 
+    // Input data goes in after the program, so it isn't overwritten by the program's data or bss
+    for (path, address) in &loads{
+        let data = match std::fs::read(path){
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Couldn't read {}: {}", path, e);
+                return ERROR_EXIT_CODE;
+            }
+        };
+        if *address as usize + data.len() > virtual_machine.memory.size(){
+            eprintln!("Couldn't load {}: 0x{:X} bytes at 0x{:08X} is outside of memory", path, data.len(), address);
+            return ERROR_EXIT_CODE;
+        }
+        virtual_machine.memory.write_bytes(*address as usize, &data);
+    }
+
+    // Symbols for faults, traces and the dump. main.dbv's are in main.dbg, if there is one
+    match debug_info{
+        Some(path) => match DebugInfo::load(&path){
+            Ok(mut x) => {
+                x.relocate(base);
+                virtual_machine.set_debug_info(x);
+            },
+            Err(e) => {
+                eprintln!("Couldn't load debug info {}: {}", path, e);
+                return ERROR_EXIT_CODE;
+            }
+        },
+        None => {
+            let path = std::path::Path::new(&program_path).with_extension("dbg");
+            if path.exists(){
+                match DebugInfo::load(&path){
+                    Ok(mut x) => {
+                        x.relocate(base);
+                        virtual_machine.set_debug_info(x);
+                    },
+                    Err(e) => eprintln!("Ignoring debug info {}: {}", path.display(), e),
+                }
+            }
+        },
+    }
+    virtual_machine.set_trace(trace);
+
+    let code = match virtual_machine.run(){
+        Ok(status) => {
+            println!("Program exited with status {}", status);
+            // Exit codes only have 8 bits, so keep failures from wrapping round to success
+            status.min(255) as i32
+        },
+        Err(e) => {
+            // Name the variable a bad access was to, if there's debug info
+            let accessing = match e{
+                Fault::PageFault{address, ..} | Fault::BusError{address} | Fault::Misaligned{address, ..} => {
+                    format!(", accessing {}", virtual_machine.symbolize_data(address))
+                },
+                _ => String::new(),
+            };
+            println!("Program exited with error: {:?} at {}{}", e, virtual_machine.symbolize_pc(), accessing);
+            ERROR_EXIT_CODE
+        },
+    };
+
     // Print the registers
     virtual_machine.dump();
+
+    // Output buffers are written even after a fault, to help find what went wrong
+    for (address, length, path) in &dumps{
+        if *address as usize + *length as usize > virtual_machine.memory.size(){
+            eprintln!("Couldn't dump 0x{:X} bytes at 0x{:08X}: outside of memory", length, address);
+            return ERROR_EXIT_CODE;
+        }
+
+        let mut data = vec![0; *length as usize];
+        virtual_machine.memory.read_bytes(*address as usize, &mut data);
+        if let Err(e) = std::fs::write(path, &data){
+            eprintln!("Couldn't write {}: {}", path, e);
+            return ERROR_EXIT_CODE;
+        }
+    }
+
+    code
+}
+
+// file@address - the file name can have an @ in it, so split on the last one
+fn parse_placement(spec: &str) -> (String, u32){
+    match spec.rsplit_once('@').map(|(path, address)| (path, parse_number(address))){
+        Some((path, Some(address))) if !path.is_empty() => (String::from(path), address),
+        _ => usage(),
+    }
+}
+
+// Numbers can be given in decimal, or hex with a 0x prefix
+fn parse_number(text: &str) -> Option<u32>{
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")){
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn link(args: Vec<String>) -> i32{
+    let mut linker = Linker::new();
+    let mut objects = Vec::new();
+    let mut output: Option<String> = None;
+    let mut write_debug_info = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next(){
+        match arg.as_str(){
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "-g" => write_debug_info = true,
+            "--entry" => linker.set_entry(&args.next().unwrap_or_else(|| usage())),
+            "--data-address" => linker.set_data_address(args.next().as_deref().and_then(parse_number).unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage();
+            },
+            _ => objects.push(arg),
+        }
+    }
+
+    let output = output.unwrap_or_else(|| usage());
+    if objects.is_empty(){
+        usage();
+    }
+
+    for path in &objects{
+        let object = std::fs::read(path).map_err(|e| e.to_string()).and_then(|x| Object::parse(&x).map_err(String::from));
+        match object{
+            Ok(x) => linker.add_object(path, x),
+            Err(e) => {
+                eprintln!("Couldn't read object {}: {}", path, e);
+                return ERROR_EXIT_CODE;
+            }
+        }
+    }
+
+    let (executable, debug_info) = match linker.link_with_debug_info(){
+        Ok(x) => x,
+        Err(errors) => {
+            for e in errors{
+                eprintln!("{}", e);
+            }
+            return ERROR_EXIT_CODE;
+        }
+    };
+
+    if let Err(e) = std::fs::write(&output, executable.to_bytes()){
+        eprintln!("Couldn't write {}: {}", output, e);
+        return ERROR_EXIT_CODE;
+    }
+
+    // The debug info goes next to the program, where `run` looks for it
+    if write_debug_info{
+        let path = std::path::Path::new(&output).with_extension("dbg");
+        if let Err(e) = std::fs::write(&path, debug_info.to_text()){
+            eprintln!("Couldn't write {}: {}", path.display(), e);
+            return ERROR_EXIT_CODE;
+        }
+    }
+
+    0
+}
+
+// Check a program without running it, exiting with an error if there are any issues
+fn verify_program(args: Vec<String>) -> i32{
+    let mut program_path: Option<String> = None;
+    let mut raw = false;
+
+    for arg in args{
+        match arg.as_str(){
+            "--raw" => raw = true,
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage();
+            },
+            _ => program_path = Some(arg),
+        }
+    }
+
+    let program_path = program_path.unwrap_or_else(|| usage());
+    let bytes = match std::fs::read(&program_path){
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", program_path, e);
+            return ERROR_EXIT_CODE;
+        }
+    };
+
+    let format = if raw { ImageFormat::Raw } else { ImageFormat::from_path(&program_path) };
+    let executable = match read_image(&bytes, format){
+        Ok(x) => x,
+        Err(e) => {
+            println!("{}: {}", program_path, e);
+            return ERROR_EXIT_CODE;
+        }
+    };
+
+    let issues = verify(&executable.text, executable.text_address, executable.entry);
+    if issues.is_empty(){
+        println!("{}: no issues found", program_path);
+        return 0;
+    }
+
+    println!("{}: {} issue(s), at byte offsets in the text", program_path, issues.len());
+    for issue in issues{
+        println!("0x{:08X}: {}", issue.offset, issue.message);
+    }
+
+    ERROR_EXIT_CODE
+}
+
+// Translate a program between formats, see image.rs
+fn convert(args: Vec<String>) -> i32{
+    let mut paths = Vec::new();
+    let mut from: Option<ImageFormat> = None;
+    let mut to: Option<ImageFormat> = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next(){
+        match arg.as_str(){
+            "--from" => from = Some(args.next().as_deref().and_then(ImageFormat::from_name).unwrap_or_else(|| usage())),
+            "--to" => to = Some(args.next().as_deref().and_then(ImageFormat::from_name).unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage();
+            },
+            _ => paths.push(arg),
+        }
+    }
+
+    let (input, output) = match paths.as_slice(){
+        [input, output] => (input, output),
+        _ => usage(),
+    };
+    let from = from.unwrap_or_else(|| ImageFormat::from_path(input));
+    let to = to.unwrap_or_else(|| ImageFormat::from_path(output));
+
+    let executable = match std::fs::read(input).map_err(|e| e.to_string()).and_then(|x| read_image(&x, from)){
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", input, e);
+            return ERROR_EXIT_CODE;
+        }
+    };
+
+    match write_image(&executable, to).and_then(|x| std::fs::write(output, x).map_err(|e| e.to_string())){
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Couldn't write {}: {}", output, e);
+            ERROR_EXIT_CODE
+        }
+    }
+}
+
+// Add a hash, and a signature if there's a key, to a .dbv file - see signature.rs
+fn sign(args: Vec<String>) -> i32{
+    let mut input: Option<String> = None;
+    let mut output: Option<String> = None;
+    let mut key_path: Option<String> = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next(){
+        match arg.as_str(){
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "--key" => key_path = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage();
+            },
+            _ if input.is_none() => input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| input.clone()); // Sign in place
+
+    let key = match key_path.as_deref().map(signature::load_key).transpose(){
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Couldn't load signing key {}: {}", key_path.unwrap_or_default(), e);
+            return ERROR_EXIT_CODE;
+        }
+    };
+
+    // Only sign things that load
+    let sealed = std::fs::read(&input).map_err(|e| e.to_string())
+        .and_then(|x| read_image(&x, ImageFormat::Dbv).map(|_| x))
+        .and_then(|x| signature::seal(&x, key.as_ref()));
+    let sealed = match sealed{
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Couldn't sign {}: {}", input, e);
+            return ERROR_EXIT_CODE;
+        }
+    };
+
+    if let Err(e) = std::fs::write(&output, sealed){
+        eprintln!("Couldn't write {}: {}", output, e);
+        return ERROR_EXIT_CODE;
+    }
+
+    0
+}
+
+// Make a signing key, and a .pub file with its public key for the trusted keys file
+fn keygen(args: Vec<String>) -> i32{
+    let mut path: Option<std::path::PathBuf> = None;
+    let mut force = false;
+
+    for arg in args{
+        match arg.as_str(){
+            "--force" => force = true,
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage();
+            },
+            _ if path.is_none() && !arg.starts_with('-') && !arg.ends_with(".pub") => path = Some(std::path::PathBuf::from(arg)),
+            _ => usage(),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| usage());
+    let public_path = path.with_extension("pub");
+
+    // Don't lose a key that's already in use, unless asked to
+    for x in [&path, &public_path]{
+        if !force && x.symlink_metadata().is_ok(){
+            eprintln!("{} already exists (use --force to replace it)", x.display());
+            return ERROR_EXIT_CODE;
+        }
+    }
+
+    let key = match signature::generate_key(){
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Couldn't generate a key: {}", e);
+            return ERROR_EXIT_CODE;
+        }
+    };
+
+    let public_key = signature::to_hex(key.verifying_key().as_bytes());
+    let written = write_key(&path, &signature::to_hex(key.as_bytes()), true)
+        .and_then(|_| write_key(&public_path, &public_key, false));
+    if let Err(e) = written{
+        eprintln!("Couldn't write the key: {}", e);
+        return ERROR_EXIT_CODE;
+    }
+
+    println!("{}", public_key);
+    0
+}
+
+// Write a new key file. Old files are removed rather than written over, so a secret key is never
+// left readable by others
+fn write_key(path: &std::path::Path, key: &str, secret: bool) -> std::io::Result<()>{
+    use std::io::Write;
+
+    match std::fs::remove_file(path){
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {},
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(if secret { 0o600 } else { 0o644 });
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+
+    options.open(path)?.write_all(format!("{}\n", key).as_bytes())
 }
@@ -1,136 +1,280 @@
-const MEMORY_SIZE: usize = 0xFFFFFF;
+use std::sync::Arc;
 
+pub const MEMORY_SIZE: usize = 0xFFFFFF;
+
+// How the VM treats 16 and 32-bit accesses that aren't aligned to their size
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AlignmentPolicy{
+    Allow,   // Access memory at any byte offset (default)
+    Fault,   // Raise a Misaligned fault
+    Emulate, // Split the access into byte accesses, and count how often it happens
+}
+
+// A value that can be read from and written to memory, stored little endian
+pub trait MemoryValue: Copy{
+    const SIZE: usize;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+    fn to_le_slice(self, bytes: &mut [u8]);
+}
+
+macro_rules! memory_value_impl {
+    ($($t:ty),*) => {
+        $(
+            impl MemoryValue for $t{
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn from_le_slice(bytes: &[u8]) -> Self{
+                    let mut raw = [0u8; std::mem::size_of::<$t>()];
+                    raw.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(raw)
+                }
+
+                fn to_le_slice(self, bytes: &mut [u8]){
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    }
+}
+
+memory_value_impl!(u8, i8, u16, i16, u32, i32);
+
+// Memory is split into pages, shared between forked VMs until one of them writes to it (copy-on-write)
+const MEMORY_PAGE_SIZE: usize = 0x1000;
+const MEMORY_PAGE_COUNT: usize = MEMORY_SIZE.div_ceil(MEMORY_PAGE_SIZE);
+
+type Page = [u8; MEMORY_PAGE_SIZE];
+
+#[derive(Clone)]
 pub struct Memory{
-    memory: Vec<u8>,
+    pages: Vec<Arc<Page>>, // Cloning only clones the page references, not the pages
+}
+
+impl Default for Memory{
+    fn default() -> Self{
+        Self::new()
+    }
 }
 
 impl Memory{
     pub fn new() -> Self{
+        // Every page starts out as the same shared page, so untouched memory costs nothing
+        let page = Arc::new([0x1; MEMORY_PAGE_SIZE]);
+
         Memory{
-            memory: vec![0x1; MEMORY_SIZE],
+            pages: vec![page; MEMORY_PAGE_COUNT],
         }
     }
 
-    pub fn get_memory(&self, address: usize) -> u32{
-        let mut value: u32 = 0;
-        for i in 0..4{
-            value |= (self.memory[address + i] as u32) << (i * 8);
-        }
-        value
+    pub fn size(&self) -> usize{
+        MEMORY_SIZE
     }
 
-    pub fn get_memory_u16(&self, address: usize) -> u32{
-        let mut value: u16 = 0;
-        for i in 0..2{
-            // Little Endian
-            value |= (self.memory[address + i] as u16) << (i * 8);
+    fn check_bounds(&self, address: usize, length: usize){
+        if address.checked_add(length).is_none_or(|end| end > MEMORY_SIZE){
+            panic!("Memory access out of bounds: 0x{:X} ({} bytes)", address, length);
         }
+    }
 
-        value as u32
+    // Read a little endian value of any MemoryValue type (eg: memory.read::<i16>(address))
+    pub fn read<T: MemoryValue>(&self, address: usize) -> T{
+        let mut raw = [0u8; 4];
+        self.read_bytes(address, &mut raw[..T::SIZE]);
+        T::from_le_slice(&raw[..T::SIZE])
     }
 
-    pub fn get_memory_u8(&self, address: usize) -> u32{
-        self.memory[address] as u32
+    // Write a value of any MemoryValue type, little endian
+    pub fn write<T: MemoryValue>(&mut self, address: usize, value: T){
+        let mut raw = [0u8; 4];
+        value.to_le_slice(&mut raw[..T::SIZE]);
+        self.write_bytes(address, &raw[..T::SIZE]);
     }
 
-    pub fn get_memory_u16_signed(&self, address: usize) -> u32{
-        // We don't read it as signed, but we return it as signed
-        // We do this by extending the sign bit to 32 bits
-        let mut value: u32 = 0;
-        for i in 0..2{
-            // Little Endian
-            value |= (self.memory[address + i] as u32) << (i * 8);
+    // Copy `buffer.len()` bytes starting at `address` into `buffer`
+    pub fn read_bytes(&self, address: usize, buffer: &mut [u8]){
+        self.check_bounds(address, buffer.len());
+
+        let mut done = 0;
+        while done < buffer.len(){
+            let page = (address + done) / MEMORY_PAGE_SIZE;
+            let offset = (address + done) % MEMORY_PAGE_SIZE;
+            let length = (MEMORY_PAGE_SIZE - offset).min(buffer.len() - done);
+
+            buffer[done..done + length].copy_from_slice(&self.pages[page][offset..offset + length]);
+            done += length;
         }
+    }
 
-        let sign_bit = (value & 0x8000) >> 15;
-        value |= sign_bit << 16;
+    // Copy `data` into memory starting at `address`
+    pub fn write_bytes(&mut self, address: usize, data: &[u8]){
+        self.check_bounds(address, data.len());
 
-        value as u32
+        let mut done = 0;
+        while done < data.len(){
+            let page = (address + done) / MEMORY_PAGE_SIZE;
+            let offset = (address + done) % MEMORY_PAGE_SIZE;
+            let length = (MEMORY_PAGE_SIZE - offset).min(data.len() - done);
+
+            // Copies the page first if it's shared
+            Arc::make_mut(&mut self.pages[page])[offset..offset + length].copy_from_slice(&data[done..done + length]);
+            done += length;
+        }
     }
 
-    pub fn get_memory_u8_signed(&self, address: usize) -> u32{
-        // We don't read it as signed, but we return it as signed
-        // We do this by extending the sign bit to 32 bits
-        let mut value = self.memory[address] as u32;
+    // Set `length` bytes starting at `address` to `value`
+    pub fn fill(&mut self, address: usize, length: usize, value: u8){
+        self.check_bounds(address, length);
+
+        let mut done = 0;
+        while done < length{
+            let page = (address + done) / MEMORY_PAGE_SIZE;
+            let offset = (address + done) % MEMORY_PAGE_SIZE;
+            let chunk = (MEMORY_PAGE_SIZE - offset).min(length - done);
+
+            Arc::make_mut(&mut self.pages[page])[offset..offset + chunk].fill(value);
+            done += chunk;
+        }
+    }
+
+    // Copy `length` bytes from `source` to `destination`. The regions may overlap
+    pub fn copy_within(&mut self, source: usize, destination: usize, length: usize){
+        let mut buffer = vec![0u8; length];
+        self.read_bytes(source, &mut buffer);
+        self.write_bytes(destination, &buffer);
+    }
+
+    pub fn get_memory(&self, address: usize) -> u32{
+        self.read::<u32>(address)
+    }
+
+    pub fn get_memory_u16(&self, address: usize) -> u32{
+        self.read::<u16>(address) as u32
+    }
+
+    pub fn get_memory_u8(&self, address: usize) -> u32{
+        self.read::<u8>(address) as u32
+    }
 
-        let sign_bit = (value & 0x80) >> 7;
-        value |= sign_bit << 8;
+    pub fn get_memory_u16_signed(&self, address: usize) -> u32{
+        // Read it as signed, then sign extend it to 32 bits
+        self.read::<i16>(address) as i32 as u32
+    }
 
-        value as u32
+    pub fn get_memory_u8_signed(&self, address: usize) -> u32{
+        // Read it as signed, then sign extend it to 32 bits
+        self.read::<i8>(address) as i32 as u32
     }
 
     pub fn set_memory(&mut self, address: usize, value: u32){
-        for i in 0..4{
-            self.memory[address + i] = ((value >> (i * 8)) & 0xFF) as u8;
-        }
+        self.write::<u32>(address, value);
     }
 
     pub fn set_memory_u16(&mut self, address: usize, value: u32){
-        for i in 0..2{
-            // Little Endian
-            self.memory[address + i] = ((value >> (i * 8)) & 0xFF) as u8;
-        }
+        self.write::<u16>(address, value as u16);
     }
 
     pub fn set_memory_u8(&mut self, address: usize, value: u32){
-        self.memory[address] = (value & 0xFF) as u8;
+        self.write::<u8>(address, value as u8);
     }
 }
 
 
-pub fn test_memory() -> bool{
-    // Test setting, loading, and signed loading
-    
-    let mut memory = Memory::new();
-
-    // Setting
-    memory.set_memory(0x000000, 0x12345678);
-    memory.set_memory(0x000004, 0x87654321);
-    memory.set_memory(0x000008, 0x00000000);
-    memory.set_memory(0x00000C, 0xFFFFFFFF);
-
-    // 16 bit setting
-    memory.set_memory_u16(0x000010, 0x1234);
-    memory.set_memory_u16(0x000012, 0x5678);
-    memory.set_memory_u16(0x000014, 0x0000);
-    memory.set_memory_u16(0x000016, 0xFFFF);
-
-    // 8 bit setting
-    memory.set_memory_u8(0x000018, 0x12);
-    memory.set_memory_u8(0x000019, 0x34);
-    memory.set_memory_u8(0x00001A, 0x56);
-    memory.set_memory_u8(0x00001B, 0x78);
-
-    // Loading
-    assert_eq!(memory.get_memory(0x000000), 0x12345678);
-    assert_eq!(memory.get_memory(0x000004), 0x87654321);
-    assert_eq!(memory.get_memory(0x000008), 0x00000000);
-    assert_eq!(memory.get_memory(0x00000C), 0xFFFFFFFF);
-
-    // 16 bit loading
-    assert_eq!(memory.get_memory_u16(0x000010), 0x1234);
-    assert_eq!(memory.get_memory_u16(0x000012), 0x5678);
-    assert_eq!(memory.get_memory_u16(0x000014), 0x0000);
-    assert_eq!(memory.get_memory_u16(0x000016), 0xFFFF);
-
-    // 8 bit loading
-    assert_eq!(memory.get_memory_u8(0x000018), 0x12);
-    assert_eq!(memory.get_memory_u8(0x000019), 0x34);
-    assert_eq!(memory.get_memory_u8(0x00001A), 0x56);
-    assert_eq!(memory.get_memory_u8(0x00001B), 0x78);
-
-    // Set signed values. Then load and check they're properly extended
-    memory.set_memory_u16(0x00001C, 0x8000); // 0b1000000000000000 -> should be -32768
-    memory.set_memory_u16(0x00001E, 0x7FFF); // 0b0111111111111111 -> should be 32767
-    memory.set_memory_u8(0x000020, 0x80); // 0b10000000 -> should be -128
-    memory.set_memory_u8(0x000021, 0x7F); // 0b01111111 -> should be 127
-
-    // Load signed values - load them first, then check they're properly extended. 
-    // Convert to i32 to check the sign bit
-    assert_eq!(memory.get_memory_u16_signed(0x00001C) as u16 as i16, -32768);
-    assert_eq!(memory.get_memory_u16_signed(0x00001E) as u16 as i16, 32767);
-    assert_eq!(memory.get_memory_u8_signed(0x000020) as u8 as i8, -128);
-    assert_eq!(memory.get_memory_u8_signed(0x000021) as u8 as i8, 127);
-
-    true
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn memory_reads_and_writes(){
+        // Test setting, loading, and signed loading
+
+        let mut memory = Memory::new();
+
+        // Setting
+        memory.set_memory(0x000000, 0x12345678);
+        memory.set_memory(0x000004, 0x87654321);
+        memory.set_memory(0x000008, 0x00000000);
+        memory.set_memory(0x00000C, 0xFFFFFFFF);
+
+        // 16 bit setting
+        memory.set_memory_u16(0x000010, 0x1234);
+        memory.set_memory_u16(0x000012, 0x5678);
+        memory.set_memory_u16(0x000014, 0x0000);
+        memory.set_memory_u16(0x000016, 0xFFFF);
+
+        // 8 bit setting
+        memory.set_memory_u8(0x000018, 0x12);
+        memory.set_memory_u8(0x000019, 0x34);
+        memory.set_memory_u8(0x00001A, 0x56);
+        memory.set_memory_u8(0x00001B, 0x78);
+
+        // Loading
+        assert_eq!(memory.get_memory(0x000000), 0x12345678);
+        assert_eq!(memory.get_memory(0x000004), 0x87654321);
+        assert_eq!(memory.get_memory(0x000008), 0x00000000);
+        assert_eq!(memory.get_memory(0x00000C), 0xFFFFFFFF);
+
+        // 16 bit loading
+        assert_eq!(memory.get_memory_u16(0x000010), 0x1234);
+        assert_eq!(memory.get_memory_u16(0x000012), 0x5678);
+        assert_eq!(memory.get_memory_u16(0x000014), 0x0000);
+        assert_eq!(memory.get_memory_u16(0x000016), 0xFFFF);
+
+        // 8 bit loading
+        assert_eq!(memory.get_memory_u8(0x000018), 0x12);
+        assert_eq!(memory.get_memory_u8(0x000019), 0x34);
+        assert_eq!(memory.get_memory_u8(0x00001A), 0x56);
+        assert_eq!(memory.get_memory_u8(0x00001B), 0x78);
+
+        // Set signed values. Then load and check they're properly extended
+        memory.set_memory_u16(0x00001C, 0x8000); // 0b1000000000000000 -> should be -32768
+        memory.set_memory_u16(0x00001E, 0x7FFF); // 0b0111111111111111 -> should be 32767
+        memory.set_memory_u8(0x000020, 0x80); // 0b10000000 -> should be -128
+        memory.set_memory_u8(0x000021, 0x7F); // 0b01111111 -> should be 127
+
+        // Load signed values - load them first, then check they're properly extended.
+        // Convert to i32 to check the sign bit
+        assert_eq!(memory.get_memory_u16_signed(0x00001C) as i32, -32768);
+        assert_eq!(memory.get_memory_u16_signed(0x00001E) as i32, 32767);
+        assert_eq!(memory.get_memory_u8_signed(0x000020) as i32, -128);
+        assert_eq!(memory.get_memory_u8_signed(0x000021) as i32, 127);
+
+        // Typed reads and writes
+        memory.write::<i16>(0x000022, -2);
+        assert_eq!(memory.read::<u16>(0x000022), 0xFFFE);
+        assert_eq!(memory.read::<i16>(0x000022), -2);
+        memory.write::<i32>(0x000024, -1);
+        assert_eq!(memory.read::<u32>(0x000024), 0xFFFFFFFF);
+
+        // Bulk operations
+        let mut buffer = [0u8; 4];
+        memory.write_bytes(0x000030, &[1, 2, 3, 4]);
+        memory.read_bytes(0x000030, &mut buffer);
+        assert_eq!(buffer, [1, 2, 3, 4]);
+
+        memory.copy_within(0x000030, 0x000031, 4); // Overlapping
+        memory.read_bytes(0x000031, &mut buffer);
+        assert_eq!(buffer, [1, 2, 3, 4]);
+
+        memory.fill(0x000030, 4, 0xAA);
+        assert_eq!(memory.get_memory(0x000030), 0xAAAAAAAA);
+    }
+
+    #[test]
+    fn bulk_operations_cross_pages(){
+        let mut memory = Memory::new();
+        let data: Vec<u8> = (0..=255).collect();
+
+        let address = MEMORY_PAGE_SIZE - 100;
+        memory.write_bytes(address, &data);
+        let mut buffer = [0u8; 256];
+        memory.read_bytes(address, &mut buffer);
+        assert_eq!(buffer.as_slice(), data.as_slice());
+
+        // Forks share pages until one side writes
+        let copy = memory.clone();
+        memory.fill(address, 256, 0);
+        copy.read_bytes(address, &mut buffer);
+        assert_eq!(buffer.as_slice(), data.as_slice());
+    }
+}
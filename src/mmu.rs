@@ -0,0 +1,166 @@
+use crate::fault::Fault;
+use crate::memory::Memory;
+
+// Page Table Design:
+//
+// The MMU is enabled by setting bit 0 of the PTBR control register.
+// The rest of PTBR (bits 31..12) is the physical address of a single level
+// page table, which must be page aligned.
+//
+// The virtual address space is the same size as physical memory (24 bits),
+// split into 4096 pages of 4KiB. The page table holds one 32-bit little endian
+// entry per page, so it is 16KiB in size.
+//
+// | 0000_0000_0000_0000_0000 | 0000_0000_00 | 0 | 0 | -> 32 bits
+// |    Physical Frame        |    Unused    | W | P |
+//
+// P: Present - the page is mapped
+// W: Writable - the page can be stored to
+//
+// Virtual address: | 0000_0000 | 0000_0000_0000 | 0000_0000_0000 |
+//                  |  Unused   |      Page      |     Offset     |
+
+pub const PAGE_SIZE: u32 = 0x1000;
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_COUNT: u32 = 0x1000;
+
+pub const PTBR_ENABLE: u32 = 0x1;
+
+pub const PTE_PRESENT: u32 = 0x1;
+pub const PTE_WRITABLE: u32 = 0x2;
+pub const PTE_FRAME_MASK: u32 = 0xFFFFF000;
+
+const TLB_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry{
+    page: u32,
+    entry: u32,
+}
+
+pub struct Mmu{
+    tlb: [Option<TlbEntry>; TLB_SIZE], // Direct mapped, indexed by the low bits of the page number
+}
+
+impl Default for Mmu{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl Mmu{
+    pub fn new() -> Self{
+        Mmu{
+            tlb: [None; TLB_SIZE],
+        }
+    }
+
+    pub fn flush(&mut self){
+        self.tlb = [None; TLB_SIZE];
+    }
+
+    // Translate a virtual address to a physical one, walking the page table in `memory` on a TLB miss.
+    // If the MMU is disabled (bit 0 of ptbr clear), addresses are passed through untouched.
+    pub fn translate(&mut self, ptbr: u32, memory: &Memory, address: u32, write: bool) -> Result<usize, Fault>{
+        if ptbr & PTBR_ENABLE == 0{
+            return Ok(address as usize);
+        }
+
+        let page = address >> PAGE_SHIFT;
+        if page >= PAGE_COUNT{
+            return Err(Fault::PageFault{address, write});
+        }
+
+        let slot = page as usize % TLB_SIZE;
+        let entry = match self.tlb[slot]{
+            Some(cached) if cached.page == page => cached.entry,
+            _ => {
+                let entry_address = (ptbr & PTE_FRAME_MASK) as usize + (page * 4) as usize;
+                if entry_address + 4 > memory.size(){
+                    return Err(Fault::PageFault{address, write});
+                }
+
+                let entry = memory.get_memory(entry_address);
+                // Only cache valid entries, so a handler mapping the page doesn't need to flush
+                if entry & PTE_PRESENT != 0{
+                    self.tlb[slot] = Some(TlbEntry{page, entry});
+                }
+                entry
+            }
+        };
+
+        if entry & PTE_PRESENT == 0 || (write && entry & PTE_WRITABLE == 0){
+            return Err(Fault::PageFault{address, write});
+        }
+
+        let physical = (entry & PTE_FRAME_MASK) as usize + (address & (PAGE_SIZE - 1)) as usize;
+        if physical >= memory.size(){
+            return Err(Fault::PageFault{address, write});
+        }
+
+        Ok(physical)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const TABLE: u32 = 0x10000;
+
+    // A page table with nothing mapped but `mappings` (page, entry)
+    fn memory(mappings: &[(u32, u32)]) -> Memory{
+        let mut memory = Memory::new();
+        memory.fill(TABLE as usize, (PAGE_COUNT * 4) as usize, 0);
+        for (page, entry) in mappings{
+            memory.write::<u32>((TABLE + page * 4) as usize, *entry);
+        }
+        memory
+    }
+
+    #[test]
+    fn disabled_mmu_passes_addresses_through(){
+        let mut mmu = Mmu::new();
+        assert_eq!(mmu.translate(TABLE, &memory(&[]), 0x5678, true), Ok(0x5678));
+    }
+
+    #[test]
+    fn pages_map_to_their_frames(){
+        let memory = memory(&[(1, 0x20000 | PTE_PRESENT | PTE_WRITABLE), (2, 0x30000 | PTE_PRESENT), (3, 0x40000 | PTE_WRITABLE)]);
+        let mut mmu = Mmu::new();
+        let ptbr = TABLE | PTBR_ENABLE;
+
+        assert_eq!(mmu.translate(ptbr, &memory, 0x1234, false), Ok(0x20234));
+        assert_eq!(mmu.translate(ptbr, &memory, 0x1FFF, true), Ok(0x20FFF));
+
+        // Read only
+        assert_eq!(mmu.translate(ptbr, &memory, 0x2010, false), Ok(0x30010));
+        assert_eq!(mmu.translate(ptbr, &memory, 0x2010, true), Err(Fault::PageFault{address: 0x2010, write: true}));
+
+        // Not present, even if writable
+        assert_eq!(mmu.translate(ptbr, &memory, 0x3000, false), Err(Fault::PageFault{address: 0x3000, write: false}));
+        assert_eq!(mmu.translate(ptbr, &memory, 0x0, false), Err(Fault::PageFault{address: 0x0, write: false}));
+
+        // Past the end of the virtual address space
+        assert_eq!(mmu.translate(ptbr, &memory, 0x1000000, false), Err(Fault::PageFault{address: 0x1000000, write: false}));
+    }
+
+    #[test]
+    fn changed_entries_need_a_flush(){
+        let mut memory = memory(&[(1, 0x20000 | PTE_PRESENT)]);
+        let mut mmu = Mmu::new();
+        let ptbr = TABLE | PTBR_ENABLE;
+
+        assert_eq!(mmu.translate(ptbr, &memory, 0x1004, false), Ok(0x20004));
+        memory.write::<u32>((TABLE + 4) as usize, 0x30000 | PTE_PRESENT);
+        assert_eq!(mmu.translate(ptbr, &memory, 0x1004, false), Ok(0x20004));
+
+        mmu.flush();
+        assert_eq!(mmu.translate(ptbr, &memory, 0x1004, false), Ok(0x30004));
+
+        // Missing pages aren't cached, so mapping one takes effect straight away
+        assert!(mmu.translate(ptbr, &memory, 0x2000, false).is_err());
+        memory.write::<u32>((TABLE + 8) as usize, 0x40000 | PTE_PRESENT);
+        assert_eq!(mmu.translate(ptbr, &memory, 0x2000, false), Ok(0x40000));
+    }
+}
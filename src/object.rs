@@ -0,0 +1,324 @@
+use crate::executable::{words, FORMAT_VERSION, ISA_VERSION};
+use crate::utils::code_addresses;
+
+// Object Format:
+//
+// Object files are pieces of a program, joined into an executable by the linker (see linker.rs).
+// Like executables, every field is a 32-bit big endian word.
+//
+// | Offset | Field       | Description                                              |
+// | 0x00   | MAGIC       | "DBO\0"                                                  |
+// | 0x04   | VERSION     | Format version (high 16 bits), ISA version (low 16 bits) |
+// | 0x08   | TEXT        | Size of the instructions in bytes                        |
+// | 0x0C   | DATA        | Size of the initialised data in bytes                    |
+// | 0x10   | BSS         | Size of the zeroed data in bytes                         |
+// | 0x14   | SYMBOLS     | Number of symbols                                        |
+// | 0x18   | RELOCATIONS | Number of relocations                                    |
+// | 0x1C   | STRINGS     | Size of the string table in bytes                        |
+//
+// The header is followed by the text, the data (padded to a multiple of 4 bytes), the symbols,
+// the relocations and the string table.
+//
+// Symbol (4 words):     | Name (string table offset) | Section | Value (offset in the section) | Binding |
+// Relocation (4 words): | Section | Offset (of the word to patch) | Symbol index | Kind |
+//
+// Sections are 0 for undefined symbols, 1 for text, 2 for data and 3 for bss.
+// Local symbols can only be used by their own object, global symbols by every object.
+// Names in the string table are NUL terminated.
+//
+// Relocations only patch whole words - big endian in text, little endian in data (like memory).
+// Absolute relocations add the symbol's address to the word already there, so `label + 8` is
+// stored as 8. Data and bss symbols have memory addresses. Text symbols have code addresses, used
+// by jumps - their value is the byte offset of an instruction in the object's text, which the
+// linker turns into a code address (4 per instruction, see executable.rs).
+//
+// Relative relocations patch the extension word of a relative jump (see utils::is_relative) with
+// the distance from the jump to a text symbol, plus the word. They let objects call each other
+// without leaving anything for the loader to relocate.
+
+pub const OBJECT_MAGIC: u32 = 0x44424F00; // "DBO\0"
+
+pub const OBJECT_HEADER_SIZE: usize = 0x20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Section{
+    Undefined = 0,
+    Text = 1,
+    Data = 2,
+    Bss = 3,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Binding{
+    Local = 0,
+    Global = 1,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RelocationKind{
+    Absolute = 0, // The word becomes the symbol's address plus the word
+    Relative = 1, // The word becomes the symbol's code address minus the jump's, plus the word
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol{
+    pub name: String,
+    pub section: Section,
+    pub value: u32,
+    pub binding: Binding,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation{
+    pub section: Section, // Text or Data
+    pub offset: u32,
+    pub symbol: u32, // Index into the object's symbols
+    pub kind: RelocationKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Object{
+    pub text: Vec<u32>,
+    pub data: Vec<u8>,
+    pub bss_size: u32,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object{
+    pub fn parse(bytes: &[u8]) -> Result<Self, &'static str>{
+        if bytes.len() < OBJECT_HEADER_SIZE{
+            return Err("File is too small to be an object");
+        }
+
+        let header = words(&bytes[..OBJECT_HEADER_SIZE])?;
+        if header[0] != OBJECT_MAGIC{
+            return Err("Not a dbv object file");
+        }
+        if header[1] != (FORMAT_VERSION << 16) | ISA_VERSION{
+            return Err("Unsupported object format or ISA version");
+        }
+
+        let text_size = header[2] as usize;
+        let data_size = header[3] as usize;
+        let padded_data_size = data_size.next_multiple_of(4);
+        let symbols_size = header[5] as usize * 16;
+        let relocations_size = header[6] as usize * 16;
+        let strings_size = header[7] as usize;
+
+        let expected = OBJECT_HEADER_SIZE + text_size + padded_data_size + symbols_size + relocations_size + strings_size;
+        if bytes.len() != expected{
+            return Err("Object file size doesn't match its header");
+        }
+
+        let mut offset = OBJECT_HEADER_SIZE;
+        let mut take = |size: usize| {
+            let slice = &bytes[offset..offset + size];
+            offset += size;
+            slice
+        };
+
+        let text = words(take(text_size))?;
+        let data = take(padded_data_size)[..data_size].to_vec();
+        let symbol_words = words(take(symbols_size))?;
+        let relocation_words = words(take(relocations_size))?;
+        let strings = take(strings_size);
+
+        let mut symbols = Vec::new();
+        for symbol in symbol_words.chunks_exact(4){
+            symbols.push(Symbol{
+                name: read_string(strings, symbol[0])?,
+                section: section_from_u32(symbol[1])?,
+                value: symbol[2],
+                binding: match symbol[3]{
+                    0 => Binding::Local,
+                    1 => Binding::Global,
+                    _ => return Err("Invalid symbol binding"),
+                },
+            });
+        }
+
+        let mut relocations = Vec::new();
+        for relocation in relocation_words.chunks_exact(4){
+            relocations.push(Relocation{
+                section: section_from_u32(relocation[0])?,
+                offset: relocation[1],
+                symbol: relocation[2],
+                kind: match relocation[3]{
+                    0 => RelocationKind::Absolute,
+                    1 => RelocationKind::Relative,
+                    _ => return Err("Invalid relocation kind"),
+                },
+            });
+        }
+
+        let object = Object{
+            text,
+            data,
+            bss_size: header[4],
+            symbols,
+            relocations,
+        };
+
+        object.validate()?;
+        Ok(object)
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str>{
+        let addresses = code_addresses(&self.text);
+
+        for symbol in &self.symbols{
+            let size = match symbol.section{
+                Section::Undefined => {
+                    if symbol.binding != Binding::Global{
+                        return Err("Undefined symbols must be global");
+                    }
+                    continue;
+                },
+                Section::Text => self.text.len() as u32 * 4,
+                Section::Data => self.data.len() as u32,
+                Section::Bss => self.bss_size,
+            };
+
+            // A symbol can point at the end of its section (eg: the end of an array)
+            if symbol.value > size{
+                return Err("Symbol is outside of its section");
+            }
+            if symbol.section == Section::Text && (!symbol.value.is_multiple_of(4) || addresses.get(symbol.value as usize / 4) == Some(&None)){
+                return Err("Text symbol isn't at the start of an instruction");
+            }
+        }
+
+        for relocation in &self.relocations{
+            let size = match relocation.section{
+                Section::Text => self.text.len() as u32 * 4,
+                Section::Data => self.data.len() as u32,
+                _ => return Err("Relocations can only patch text or data"),
+            };
+
+            if !relocation.offset.is_multiple_of(4) || relocation.offset as u64 + 4 > size as u64{
+                return Err("Relocation is outside of its section");
+            }
+            if relocation.symbol as usize >= self.symbols.len(){
+                return Err("Relocation refers to a symbol that doesn't exist");
+            }
+            if relocation.kind == RelocationKind::Relative{
+                let symbol = &self.symbols[relocation.symbol as usize];
+                if relocation.section != Section::Text || addresses.get(relocation.offset as usize / 4) != Some(&None){
+                    return Err("Relative relocations can only patch the extension word of a jump");
+                }
+                if !matches!(symbol.section, Section::Text | Section::Undefined){
+                    return Err("Relative relocations can only refer to text symbols");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut strings = Vec::new();
+        let mut symbol_words = Vec::new();
+        for symbol in &self.symbols{
+            symbol_words.extend_from_slice(&[strings.len() as u32, symbol.section as u32, symbol.value, symbol.binding as u32]);
+            strings.extend_from_slice(symbol.name.as_bytes());
+            strings.push(0);
+        }
+
+        let header = [
+            OBJECT_MAGIC,
+            (FORMAT_VERSION << 16) | ISA_VERSION,
+            self.text.len() as u32 * 4,
+            self.data.len() as u32,
+            self.bss_size,
+            self.symbols.len() as u32,
+            self.relocations.len() as u32,
+            strings.len() as u32,
+        ];
+
+        let mut bytes = Vec::new();
+        for word in header.iter().chain(self.text.iter()){
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.data);
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+
+        for word in symbol_words.iter(){
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        for relocation in &self.relocations{
+            for word in [relocation.section as u32, relocation.offset, relocation.symbol, relocation.kind as u32]{
+                bytes.extend_from_slice(&word.to_be_bytes());
+            }
+        }
+        bytes.extend_from_slice(&strings);
+
+        bytes
+    }
+}
+
+fn section_from_u32(value: u32) -> Result<Section, &'static str>{
+    match value{
+        0 => Ok(Section::Undefined),
+        1 => Ok(Section::Text),
+        2 => Ok(Section::Data),
+        3 => Ok(Section::Bss),
+        _ => Err("Invalid section"),
+    }
+}
+
+fn read_string(strings: &[u8], offset: u32) -> Result<String, &'static str>{
+    let start = strings.get(offset as usize..).ok_or("Symbol name is outside of the string table")?;
+    let end = start.iter().position(|x| *x == 0).ok_or("Symbol name isn't terminated")?;
+
+    String::from_utf8(start[..end].to_vec()).map_err(|_| "Symbol name isn't valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn object() -> Object{
+        let symbol = |name: &str, section: Section, value: u32, binding: Binding| Symbol{name: String::from(name), section, value, binding};
+
+        Object{
+            text: vec![0x1F500001, 0, 0x03401001, 0, 0x00000000], // JMP relative, SET R1 with an extension word, HLT
+            data: vec![1, 2, 3, 4, 5], // Padded in the file
+            bss_size: 0x10,
+            symbols: vec![
+                symbol("_start", Section::Text, 0, Binding::Global),
+                symbol("table", Section::Data, 4, Binding::Local),
+                symbol("buffer", Section::Bss, 0x10, Binding::Global),
+                symbol("helper", Section::Undefined, 0, Binding::Global),
+            ],
+            relocations: vec![
+                Relocation{section: Section::Text, offset: 4, symbol: 3, kind: RelocationKind::Relative},
+                Relocation{section: Section::Text, offset: 12, symbol: 2, kind: RelocationKind::Absolute},
+                Relocation{section: Section::Data, offset: 0, symbol: 1, kind: RelocationKind::Absolute},
+            ],
+        }
+    }
+
+    #[test]
+    fn objects_round_trip(){
+        assert_eq!(Object::parse(&object().to_bytes()), Ok(object()));
+    }
+
+    #[test]
+    fn invalid_objects_are_refused(){
+        let bytes = object().to_bytes();
+        assert_eq!(Object::parse(&bytes[..bytes.len() - 1]), Err("Object file size doesn't match its header"));
+
+        let mut relative_data = object();
+        relative_data.relocations[2].kind = RelocationKind::Relative;
+        assert_eq!(Object::parse(&relative_data.to_bytes()), Err("Relative relocations can only patch the extension word of a jump"));
+
+        let mut mid_instruction = object();
+        mid_instruction.symbols[0].value = 4; // The extension word of the JMP
+        assert_eq!(Object::parse(&mid_instruction.to_bytes()), Err("Text symbol isn't at the start of an instruction"));
+
+        let mut local_undefined = object();
+        local_undefined.symbols[3].binding = Binding::Local;
+        assert_eq!(local_undefined.validate(), Err("Undefined symbols must be global"));
+    }
+}
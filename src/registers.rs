@@ -1,3 +1,5 @@
+use crate::enum_conv_gen;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Register{
     pub register: usize,
@@ -13,6 +15,18 @@ impl Register{
     }
 }
 
+enum_conv_gen! {
+    #[allow(clippy::upper_case_acronyms)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ControlRegister {
+        PTBR = 0x0, // Page table base - bits 31..12 are the table address, bit 0 enables the MMU
+        FAR,        // Faulting address - set by the VM when a page fault is raised
+        EPC,        // Exception PC - byte address of the instruction that faulted
+        IVT,        // Interrupt vector table - physical address of the table, see interrupts.rs
+    }
+}
+
+#[derive(Clone)]
 pub struct Registers{
     pub registers: [Register; 16], // 16 32-bit general purpose registers
 
@@ -52,7 +66,7 @@ pub struct Registers{
 
     // Interrupt Flag
     interrupt_flag: u8, // Bitmask to see what has been set
-    // 0x0000 = undefined
+    // 0x0000 = Interrupts enabled (set by EI, cleared by DI and on entry to a handler)
     // 0x0001 = undefined
     // 0x0002 = undefined
     // 0x0003 = undefined
@@ -60,6 +74,18 @@ pub struct Registers{
     // 0x0005 = undefined
     // 0x0006 = undefined
     // 0x0007 = undefined
+
+    // Control Registers - only accessible through MTCR/MFCR
+    ptbr: u32,
+    far: u32,
+    epc: u32,
+    ivt: u32,
+}
+
+impl Default for Registers{
+    fn default() -> Self{
+        Self::new()
+    }
 }
 
 impl Registers{
@@ -73,6 +99,11 @@ impl Registers{
             cmp_flag: 0,
             arith_flag: 0,
             interrupt_flag: 0,
+
+            ptbr: 0,
+            far: 0,
+            epc: 0,
+            ivt: 0,
         }
     }
 
@@ -107,4 +138,50 @@ impl Registers{
     pub fn get_cmp_flag(&self) -> u8{
         self.cmp_flag
     }
+
+    pub fn set_arith_flag(&mut self, value: u8){
+        self.arith_flag = value;
+    }
+
+    pub fn get_arith_flag(&self) -> u8{
+        self.arith_flag
+    }
+
+    pub fn set_interrupt_flag(&mut self, value: u8){
+        self.interrupt_flag = value;
+    }
+
+    pub fn get_interrupt_flag(&self) -> u8{
+        self.interrupt_flag
+    }
+
+    // All the flags packed into one word, as they're saved on the stack when entering an interrupt handler
+    // | 0000_0000 | Interrupt | Arithmetic | Compare |
+    pub fn get_flags(&self) -> u32{
+        (self.cmp_flag as u32) | ((self.arith_flag as u32) << 8) | ((self.interrupt_flag as u32) << 16)
+    }
+
+    pub fn set_flags(&mut self, value: u32){
+        self.cmp_flag = (value & 0xFF) as u8;
+        self.arith_flag = ((value >> 8) & 0xFF) as u8;
+        self.interrupt_flag = ((value >> 16) & 0xFF) as u8;
+    }
+
+    pub fn set_control(&mut self, register: ControlRegister, value: u32){
+        match register{
+            ControlRegister::PTBR => self.ptbr = value,
+            ControlRegister::FAR => self.far = value,
+            ControlRegister::EPC => self.epc = value,
+            ControlRegister::IVT => self.ivt = value,
+        }
+    }
+
+    pub fn get_control(&self, register: ControlRegister) -> u32{
+        match register{
+            ControlRegister::PTBR => self.ptbr,
+            ControlRegister::FAR => self.far,
+            ControlRegister::EPC => self.epc,
+            ControlRegister::IVT => self.ivt,
+        }
+    }
 }
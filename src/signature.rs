@@ -0,0 +1,242 @@
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::executable::{FLAG_TRAILER, HEADER_SIZE};
+
+// Signed Images:
+//
+// `dbv sign` sets FLAG_TRAILER in a .dbv header and appends a trailer, so damaged or tampered
+// programs are refused when they're loaded:
+//
+// | Offset from the end | Field     | Description                                                   |
+// | -0x88               | HASH      | SHA-256 of everything before the trailer (header included)    |
+// | -0x68               | KEY       | Ed25519 public key of the signer, or zeros                    |
+// | -0x48               | SIGNATURE | Ed25519 signature of HASH, or zeros                           |
+// | -0x08               | FLAGS     | TRAILER_SIGNED if there's a signature (big endian, like MAGIC) |
+// | -0x04               | MAGIC     | "DBVT"                                                        |
+//
+// Executable::parse checks the hash of every image with a trailer. Signatures are only checked
+// against a set of trusted keys, when the loader is given one - then unsigned images, and images
+// signed by anyone else, are refused too.
+//
+// Key files are text. A signing key file holds the 32 byte secret key as hex, and the trusted keys
+// file holds one public key as hex per line, optionally followed by a name. Lines starting with #
+// are comments.
+
+pub const TRAILER_MAGIC: u32 = 0x44425654; // "DBVT"
+pub const TRAILER_SIZE: usize = 0x88;
+pub const TRAILER_SIGNED: u32 = 0x1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailer{
+    pub hash: [u8; 32],
+    pub signature: Option<(VerifyingKey, Signature)>,
+}
+
+// The contents of an image without its trailer, and the trailer if it has one. The hash is checked
+pub fn check(bytes: &[u8]) -> Result<(&[u8], Option<Trailer>), &'static str>{
+    let flags = match bytes.get(0x0C..0x10){
+        Some(x) if bytes.len() >= HEADER_SIZE => u32::from_be_bytes([x[0], x[1], x[2], x[3]]),
+        _ => return Ok((bytes, None)), // Too small to be an executable - the parser will say so
+    };
+    if flags & FLAG_TRAILER == 0{
+        return Ok((bytes, None));
+    }
+
+    if bytes.len() < HEADER_SIZE + TRAILER_SIZE{
+        return Err("Image is too small for its trailer");
+    }
+    let (content, trailer) = bytes.split_at(bytes.len() - TRAILER_SIZE);
+    let word = |offset: usize| u32::from_be_bytes([trailer[offset], trailer[offset + 1], trailer[offset + 2], trailer[offset + 3]]);
+    if word(0x84) != TRAILER_MAGIC{
+        return Err("Image trailer is missing");
+    }
+
+    let hash: [u8; 32] = trailer[..0x20].try_into().unwrap();
+    if hash != hash_of(content){
+        return Err("Image is corrupt, its hash doesn't match its contents");
+    }
+
+    let signature = match word(0x80){
+        0 => None,
+        TRAILER_SIGNED => {
+            let key = VerifyingKey::from_bytes(trailer[0x20..0x40].try_into().unwrap()).map_err(|_| "Image is signed with an invalid key")?;
+            Some((key, Signature::from_bytes(trailer[0x40..0x80].try_into().unwrap())))
+        },
+        _ => return Err("Unknown image trailer flags"),
+    };
+
+    Ok((content, Some(Trailer{hash, signature})))
+}
+
+// Add a trailer to an executable, replacing the one it has. It's signed if there's a key
+pub fn seal(bytes: &[u8], key: Option<&SigningKey>) -> Result<Vec<u8>, String>{
+    let (content, _) = check(bytes)?;
+    if content.len() < HEADER_SIZE{
+        return Err(String::from("File is too small to be an executable"));
+    }
+
+    let mut content = content.to_vec();
+    let flags = u32::from_be_bytes([content[0x0C], content[0x0D], content[0x0E], content[0x0F]]) | FLAG_TRAILER;
+    content[0x0C..0x10].copy_from_slice(&flags.to_be_bytes());
+
+    let hash = hash_of(&content);
+    let mut trailer = hash.to_vec();
+    match key{
+        Some(key) => {
+            trailer.extend_from_slice(key.verifying_key().as_bytes());
+            trailer.extend_from_slice(&key.sign(&hash).to_bytes());
+            trailer.extend_from_slice(&TRAILER_SIGNED.to_be_bytes());
+        },
+        None => trailer.resize(TRAILER_SIZE - 4, 0),
+    }
+    trailer.extend_from_slice(&TRAILER_MAGIC.to_be_bytes());
+
+    content.extend_from_slice(&trailer);
+    Ok(content)
+}
+
+fn hash_of(bytes: &[u8]) -> [u8; 32]{
+    Sha256::digest(bytes).into()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys{
+    keys: Vec<(VerifyingKey, String)>, // Key and name, for messages
+}
+
+impl TrustedKeys{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn load<T>(path: &T) -> Result<Self, String> where T: AsRef<Path> + ?Sized{
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String>{
+        let mut keys = TrustedKeys::new();
+
+        for (number, line) in text.lines().enumerate(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#'){
+                continue;
+            }
+
+            let (key, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let key = from_hex(key).and_then(|x| VerifyingKey::from_bytes(&x).ok())
+                .ok_or_else(|| format!("Invalid public key on line {}: {}", number + 1, line))?;
+            keys.add(key, name.trim());
+        }
+
+        Ok(keys)
+    }
+
+    pub fn add(&mut self, key: VerifyingKey, name: &str){
+        let name = if name.is_empty() { to_hex(key.as_bytes()) } else { String::from(name) };
+        self.keys.push((key, name));
+    }
+
+    // Check an image's signature, returning the name of the key that signed it
+    pub fn verify(&self, trailer: Option<&Trailer>) -> Result<&str, String>{
+        let (key, signature) = trailer.and_then(|x| x.signature.as_ref()).ok_or("Image isn't signed")?;
+        let (_, name) = self.keys.iter().find(|x| x.0 == *key).ok_or_else(|| format!("Image is signed by an untrusted key {}", to_hex(key.as_bytes())))?;
+
+        key.verify(&trailer.unwrap().hash, signature).map_err(|_| "Image signature doesn't verify")?;
+        Ok(name)
+    }
+}
+
+// A new random signing key
+pub fn generate_key() -> Result<SigningKey, String>{
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|e| e.to_string())?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+pub fn load_key<T>(path: &T) -> Result<SigningKey, String> where T: AsRef<Path> + ?Sized{
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let secret = text.lines().map(str::trim).find(|x| !x.is_empty() && !x.starts_with('#')).unwrap_or("");
+    from_hex(secret).map(|x| SigningKey::from_bytes(&x)).ok_or_else(|| String::from("Invalid signing key"))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn from_hex(text: &str) -> Option<[u8; 32]>{
+    if text.len() != 64 || !text.is_ascii(){
+        return None;
+    }
+
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate(){
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::executable::Executable;
+
+    fn program() -> Vec<u8>{
+        Executable{text: vec![0x00000000], ..Default::default()}.to_bytes() // HLT
+    }
+
+    #[test]
+    fn sealed_images_verify(){
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let sealed = seal(&program(), Some(&key)).unwrap();
+
+        let (content, trailer) = check(&sealed).unwrap();
+        assert_eq!(content.len(), program().len());
+        assert_eq!(Executable::parse(&sealed).map(|x| x.text), Ok(vec![0]));
+
+        let mut keys = TrustedKeys::new();
+        keys.add(key.verifying_key(), "test");
+        assert_eq!(keys.verify(trailer.as_ref()), Ok("test"));
+
+        // Sealing again replaces the trailer rather than adding another
+        assert_eq!(seal(&sealed, Some(&key)), Ok(sealed));
+    }
+
+    #[test]
+    fn tampered_images_are_refused(){
+        let sealed = seal(&program(), None).unwrap();
+
+        let mut tampered = sealed.clone();
+        tampered[HEADER_SIZE] ^= 0xFF;
+        assert_eq!(check(&tampered).map(|_| ()), Err("Image is corrupt, its hash doesn't match its contents"));
+        assert_eq!(Executable::parse(&tampered), Err("Image is corrupt, its hash doesn't match its contents"));
+
+        let mut tampered = sealed;
+        let hash = tampered.len() - TRAILER_SIZE;
+        tampered[hash] ^= 0xFF;
+        assert!(Executable::parse(&tampered).is_err());
+    }
+
+    #[test]
+    fn only_trusted_signatures_verify(){
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut keys = TrustedKeys::new();
+        keys.add(SigningKey::from_bytes(&[8; 32]).verifying_key(), "someone else");
+
+        let unsigned = seal(&program(), None).unwrap();
+        assert_eq!(keys.verify(check(&unsigned).unwrap().1.as_ref()), Err(String::from("Image isn't signed")));
+
+        let signed = seal(&program(), Some(&key)).unwrap();
+        assert!(keys.verify(check(&signed).unwrap().1.as_ref()).unwrap_err().starts_with("Image is signed by an untrusted key"));
+
+        // A signature copied from another image doesn't verify
+        keys.add(key.verifying_key(), "test");
+        let mut forged = seal(&Executable{text: vec![0x00000000, 0x00000000], ..Default::default()}.to_bytes(), None).unwrap();
+        let length = forged.len();
+        forged[length - 0x68..].copy_from_slice(&signed[signed.len() - 0x68..]);
+        assert_eq!(keys.verify(check(&forged).unwrap().1.as_ref()), Err(String::from("Image signature doesn't verify")));
+    }
+}
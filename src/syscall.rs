@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::enum_conv_gen;
+use crate::fault::Fault;
+use crate::fs::FsError;
+use crate::vm::VirtualMachine;
+
+// System Call Design:
+//
+// The SYS instruction takes no arguments. The call number is read from R0, and
+// arguments from R1, R2 and R3. The result is written back to R0.
+// Calls that can fail return SYSCALL_ERROR (-1) in R0. File calls return a negated
+// error code from fs.rs instead (eg: -2 for NotFound), and SYSCALL_ERROR is code 1.
+//
+// BRK:   R1 = new break (0 queries the current break). Returns the break
+// SBRK:  R1 = signed amount to grow (or shrink) the heap by. Returns the old break
+// EXIT:  R1 = exit status. Stops the VM
+// WRITE: R1 = file descriptor (1 = stdout, 2 = stderr, 3+ = open file), R2 = buffer address, R3 = length. Returns bytes written
+// READ:  R1 = file descriptor (0 = stdin, 3+ = open file), R2 = buffer address, R3 = length. Returns bytes read (0 at the end of input)
+// TIME:  Returns the host time in seconds since the unix epoch
+// OPEN:  R1 = path address, R2 = path length, R3 = OPEN_* flags from fs.rs. Returns a file descriptor
+// CLOSE: R1 = file descriptor. Returns 0
+// SEEK:  R1 = file descriptor, R2 = signed offset, R3 = 0 from the start, 1 from the current position, 2 from the end.
+//        Returns the new position
+//
+// Embedders can replace any of these, or add their own numbers, with `SyscallTable::register`
+
+pub const SYSCALL_NUMBER_REGISTER: usize = 0;
+pub const SYSCALL_ARGUMENT_REGISTERS: [usize; 3] = [1, 2, 3];
+pub const SYSCALL_RETURN_REGISTER: usize = 0;
+
+pub const SYSCALL_ERROR: u32 = 0xFFFFFFFF;
+
+enum_conv_gen! {
+    #[allow(clippy::upper_case_acronyms)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Syscall {
+        BRK = 0x0, // Set the end of the heap
+        SBRK,      // Grow or shrink the heap
+        EXIT,      // Stop the VM with an exit status
+        WRITE,     // Write bytes to stdout or stderr
+        READ,      // Read bytes from stdin
+        TIME,      // Get the host time
+        OPEN,      // Open a file in the sandbox
+        CLOSE,     // Close a file descriptor
+        SEEK,      // Move the position of a file descriptor
+    }
+}
+
+// A system call handler gets the VM and the argument registers, and returns the value for R0
+pub type SyscallHandler = Arc<dyn Fn(&mut VirtualMachine, [u32; 3]) -> Result<u32, Fault> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct SyscallTable{
+    handlers: HashMap<u32, SyscallHandler>,
+}
+
+impl Default for SyscallTable{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl SyscallTable{
+    // An empty table - every SYS raises InvalidSyscall
+    pub fn empty() -> Self{
+        SyscallTable{
+            handlers: HashMap::new(),
+        }
+    }
+
+    // The table with the built in calls
+    pub fn new() -> Self{
+        let mut table = Self::empty();
+
+        table.register(Syscall::BRK as u32, sys_brk);
+        table.register(Syscall::SBRK as u32, sys_sbrk);
+        table.register(Syscall::EXIT as u32, sys_exit);
+        table.register(Syscall::WRITE as u32, sys_write);
+        table.register(Syscall::READ as u32, sys_read);
+        table.register(Syscall::TIME as u32, sys_time);
+        table.register(Syscall::OPEN as u32, sys_open);
+        table.register(Syscall::CLOSE as u32, sys_close);
+        table.register(Syscall::SEEK as u32, sys_seek);
+
+        table
+    }
+
+    // Add a handler for `number`, replacing any existing one
+    pub fn register<F>(&mut self, number: u32, handler: F) where F: Fn(&mut VirtualMachine, [u32; 3]) -> Result<u32, Fault> + Send + Sync + 'static{
+        self.handlers.insert(number, Arc::new(handler));
+    }
+
+    pub fn unregister(&mut self, number: u32){
+        self.handlers.remove(&number);
+    }
+
+    pub fn get(&self, number: u32) -> Option<SyscallHandler>{
+        self.handlers.get(&number).cloned()
+    }
+}
+
+fn sys_brk(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    // A break of 0 just queries the current break
+    if args[0] == 0{
+        return Ok(vm.get_heap_break());
+    }
+
+    vm.brk(args[0])
+}
+
+fn sys_sbrk(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    vm.sbrk(args[0] as i32)
+}
+
+fn sys_exit(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    vm.exit(args[0]);
+    Ok(args[0])
+}
+
+fn sys_write(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    let data = vm.read_guest_bytes(args[1], args[2] as usize)?;
+
+    let result = match args[0]{
+        0 => Err(FsError::BadDescriptor),
+        1 => std::io::stdout().write_all(&data).and_then(|_| std::io::stdout().flush()).map_err(FsError::from),
+        2 => std::io::stderr().write_all(&data).map_err(FsError::from),
+        descriptor => vm.files.write(descriptor, &data).map(|_| ()),
+    };
+
+    match result{
+        Ok(_) => Ok(data.len() as u32),
+        Err(e) => Ok(e.code()),
+    }
+}
+
+fn sys_read(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    // The buffer can't be bigger than memory, so don't let the guest make the host allocate more
+    if args[2] as usize > vm.memory.size(){
+        return Ok(FsError::InvalidArgument.code());
+    }
+    let mut buffer = vec![0u8; args[2] as usize];
+    let result = match args[0]{
+        0 => std::io::stdin().read(&mut buffer).map_err(FsError::from),
+        1 | 2 => Err(FsError::BadDescriptor),
+        descriptor => vm.files.read(descriptor, &mut buffer),
+    };
+
+    let length = match result{
+        Ok(x) => x,
+        Err(e) => return Ok(e.code()),
+    };
+
+    vm.write_guest_bytes(args[1], &buffer[..length])?;
+    Ok(length as u32)
+}
+
+fn sys_time(_vm: &mut VirtualMachine, _args: [u32; 3]) -> Result<u32, Fault>{
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    Ok(time as u32)
+}
+
+fn sys_open(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    let path = vm.read_guest_bytes(args[0], args[1] as usize)?;
+    let path = match String::from_utf8(path){
+        Ok(x) => x,
+        Err(_) => return Ok(FsError::InvalidArgument.code()),
+    };
+
+    match vm.files.open(&path, args[2]){
+        Ok(descriptor) => Ok(descriptor),
+        Err(e) => Ok(e.code()),
+    }
+}
+
+fn sys_close(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    match vm.files.close(args[0]){
+        Ok(_) => Ok(0),
+        Err(e) => Ok(e.code()),
+    }
+}
+
+fn sys_seek(vm: &mut VirtualMachine, args: [u32; 3]) -> Result<u32, Fault>{
+    match vm.files.seek(args[0], args[1] as i32, args[2]){
+        // Negative values are error codes, so positions past 2GiB can't be returned
+        Ok(position) if position > i32::MAX as u64 => Ok(FsError::InvalidArgument.code()),
+        Ok(position) => Ok(position as u32),
+        Err(e) => Ok(e.code()),
+    }
+}
@@ -1,9 +1,9 @@
 use std::fmt::Debug;
 
-use crate::Memory;
-use crate::Registers;
+use crate::memory::Memory;
+use crate::registers::Registers;
 use crate::instructions::InstructionMode;
-use crate::instructions::Instructions;
+use crate::instructions::{Instructions, HLT_STATUS_REGISTER};
 
 
 #[macro_export]
@@ -52,8 +52,15 @@ impl Parameter{
 
 
 pub fn decode_instructions(raw_instructions: &[u32]) -> Vec<(Instructions, InstructionMode, Vec<Parameter>)>{
+    decode_instructions_at(raw_instructions, 0)
+}
+
+// Decode a program that will be placed at a code address. Relative jump targets (see is_relative)
+// become code addresses here, so the VM only ever sees absolute ones
+pub fn decode_instructions_at(raw_instructions: &[u32], base: u32) -> Vec<(Instructions, InstructionMode, Vec<Parameter>)>{
     let mut instructions: Vec<(Instructions, InstructionMode, Vec<Parameter>)> = Vec::new();
     let mut is_extended = false;
+    let mut relative = false;
     let mut vals: (Instructions, InstructionMode, Vec<Parameter>);
 
     for raw_instruction in raw_instructions{
@@ -61,11 +68,15 @@ pub fn decode_instructions(raw_instructions: &[u32]) -> Vec<(Instructions, Instr
             // We're extended, so we need to read the next 4 bytes as the value
             // we can also now continue as we've read the value
             let len = instructions.len();
-            let value = *raw_instruction;
+            let mut value = *raw_instruction;
+            if relative{
+                value = value.wrapping_add(base.wrapping_add((len as u32 - 1) * 4));
+            }
             instructions[len - 1].2.push(Parameter{value});
             is_extended = false;
             continue;
         }
+        relative = is_relative(*raw_instruction);
         // Get the opcode by bit masking
         let opcode = (raw_instruction & 0xFF000000) >> 24;  // 0b1111_1111_0000_0000_0000_0000_0000_0000
         // Get the mode by bit masking
@@ -73,13 +84,14 @@ pub fn decode_instructions(raw_instructions: &[u32]) -> Vec<(Instructions, Instr
         // Get the arguments by bit masking
         let arguments = raw_instruction & 0x000FFFFF;       // 0b0000_0000_0000_1111_1111_1111_1111_1111
 
-        // Convert the opcode to an instruction
+        // Convert the opcode to an instruction. Unknown opcodes are kept as UD,
+        // so executing them raises an invalid opcode exception
         let instruction = match Instructions::from_u8(opcode as u8){
-            Some(x) => x,
-            None => {
-                eprintln!("Invalid instruction: {}", opcode);
-                std::process::exit(1);
+            Some(Instructions::UD) | None => {
+                instructions.push((Instructions::UD, InstructionMode::Register, vec![Parameter{value: opcode}]));
+                continue;
             }
+            Some(x) => x,
         };
 
         // Convert the mode to an instruction mode
@@ -99,9 +111,10 @@ pub fn decode_instructions(raw_instructions: &[u32]) -> Vec<(Instructions, Instr
                 let destination_register = (arguments & 0xF000) >> 12;
                 let src_1_register = (arguments & 0x0F00) >> 8;
                 let src_2_register = (arguments & 0x00F0) >> 4;
-                // Last 4 bits are unused
+                // Last 4 bits are flags - only HLT uses them, see HLT_STATUS_REGISTER
+                let flags = arguments & 0x000F;
 
-                (instruction, mode, vec![Parameter{value: destination_register}, Parameter{value: src_1_register}, Parameter{value: src_2_register}])
+                (instruction, mode, vec![Parameter{value: destination_register}, Parameter{value: src_1_register}, Parameter{value: src_2_register}, Parameter{value: flags}])
             },
             InstructionMode::Immediate => { // This is generally used for instructions that take a value as a value (eg: ADD R1, R2, 0x00000001) - R1 is the destination register, R2 is the source register, 0x00000001 is the value
                 // The last bit of args is the extension flag
@@ -116,7 +129,12 @@ pub fn decode_instructions(raw_instructions: &[u32]) -> Vec<(Instructions, Instr
                 if extension_flag == 0x1{
                     is_extended = true; // This will be reset once the extension is read
                 }else{
-                    let value = (arguments & 0x00F0) >> 4;
+                    let mut value = (arguments & 0x00F0) >> 4;
+                    if relative{
+                        // The offset is signed, so short jumps can go back too
+                        let offset = ((value << 28) as i32 >> 28) as u32;
+                        value = offset.wrapping_add(base.wrapping_add(instructions.len() as u32 * 4));
+                    }
                     params.push(Parameter{value});
                 }
 
@@ -144,4 +162,68 @@ pub fn decode_instructions(raw_instructions: &[u32]) -> Vec<(Instructions, Instr
     }
 
     instructions
-}
\ No newline at end of file
+}
+// Does this word carry its value in the next word? Matches decode_instructions
+pub fn is_extended(raw_instruction: u32) -> bool{
+    let valid = !matches!(Instructions::from_u8((raw_instruction >> 24) as u8), Some(Instructions::UD) | None);
+    let mode = (raw_instruction & 0x00F00000) >> 22;
+
+    valid && mode == 1 && raw_instruction & 0x1 == 0x1
+}
+
+// Is this a jump with a target relative to its own code address? Bit 20 (otherwise unused) marks
+// the Immediate value of JMP, CALL or IF* as a signed offset from the instruction, so the code
+// works wherever it's placed. Without an extension word, the offset is the 4 bit value (-8 to 7)
+pub fn is_relative(raw_instruction: u32) -> bool{
+    let branch = matches!(Instructions::from_u8((raw_instruction >> 24) as u8), Some(Instructions::JMP | Instructions::CALL |
+        Instructions::IF | Instructions::IFN | Instructions::IFG | Instructions::IFL | Instructions::IFE | Instructions::IFNE));
+    let mode = (raw_instruction & 0x00F00000) >> 22;
+
+    branch && mode == 1 && raw_instruction & 0x00100000 != 0
+}
+
+// The code address of each word of a program, or None for extension words.
+// Code addresses count instructions, not words, so an instruction and its extension word are 4 bytes
+pub fn code_addresses(raw_instructions: &[u32]) -> Vec<Option<u32>>{
+    let mut addresses = Vec::with_capacity(raw_instructions.len());
+    let mut address = 0;
+
+    let mut words = raw_instructions.iter();
+    while let Some(word) = words.next(){
+        addresses.push(Some(address));
+        if is_extended(*word) && words.next().is_some(){
+            addresses.push(None);
+        }
+        address += 4;
+    }
+
+    addresses
+}
+
+// One line of assembly for a decoded instruction, eg: `ADD R1, R2, R3` or `JMP 0x18`.
+// Operands are shown by mode, as each instruction reads them differently
+pub fn disassemble(instruction: Instructions, mode: InstructionMode, args: &[Parameter]) -> String{
+    let arg = |i: usize| args.get(i).map(|x| x.value).unwrap_or(0);
+
+    match instruction{
+        Instructions::UD => return format!("UD 0x{:02X}", arg(0)),
+        Instructions::HLT if mode == InstructionMode::Register => {
+            return if arg(3) & HLT_STATUS_REGISTER != 0 { format!("HLT R{}", arg(0)) } else { String::from("HLT") };
+        },
+        Instructions::RET | Instructions::TLBF | Instructions::SYS | Instructions::EI | Instructions::DI | Instructions::IRET => {
+            return format!("{:?}", instruction);
+        },
+        Instructions::JMP | Instructions::CALL | Instructions::IF | Instructions::IFN | Instructions::IFG |
+        Instructions::IFL | Instructions::IFE | Instructions::IFNE if mode == InstructionMode::Immediate => {
+            return format!("{:?} 0x{:X}", instruction, arg(2));
+        },
+        _ => {},
+    }
+
+    match mode{
+        InstructionMode::Register => format!("{:?} R{}, R{}, R{}", instruction, arg(0), arg(1), arg(2)),
+        InstructionMode::Immediate => format!("{:?} R{}, R{}, 0x{:X}", instruction, arg(0), arg(1), arg(2)),
+        InstructionMode::RegisterIndirect => format!("{:?} R{}, [R{}], R{}", instruction, arg(0), arg(1), arg(2)),
+        InstructionMode::BaseOffset => format!("{:?} R{}, [R{} + R{} + 0x{:X}]", instruction, arg(0), arg(1), arg(2), arg(3)),
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+
+use crate::instructions::{InstructionMode, Instructions};
+use crate::registers::ControlRegister;
+use crate::syscall::{Syscall, SYSCALL_NUMBER_REGISTER};
+use crate::utils::{code_addresses, decode_instructions_at, is_extended, is_relative, Parameter};
+
+// Static Verifier:
+//
+// Checks a program without running it. Issues are reported with the byte offset of the
+// instruction word in the text:
+//
+// - Every opcode is known, and the unused mode bits are clear - bit 21 always, bit 20 unless it
+//   marks a relative jump (see utils::is_relative)
+// - Control register numbers (MTCR, MFCR) name one of the control registers. General purpose
+//   register fields are 4 bits, so they always name one of the 16 registers
+// - An instruction with the extension flag has its extension word
+// - Every Immediate jump or branch target (JMP, CALL, IF*) and the entry point is a multiple of 4,
+//   and inside the program. Code addresses count instructions (see executable.rs) from the text
+//   address, so a target can't land in an extension word, but it can land between instructions
+// - HLT or SYS EXIT is reachable from the entry point, and execution can't run past the end of
+//   the program. Jumps to registers, RET and IRET can go anywhere, so HLT is assumed to be
+//   reachable after one. SYS only counts as EXIT when the instruction before it is `SET R0, EXIT`
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue{
+    pub offset: u32, // Byte offset of the instruction in the text
+    pub message: String,
+}
+
+// Instructions that go to the code address in their Immediate value
+fn is_branch(instruction: Instructions) -> bool{
+    matches!(instruction, Instructions::JMP | Instructions::CALL | Instructions::IF | Instructions::IFN | Instructions::IFG |
+        Instructions::IFL | Instructions::IFE | Instructions::IFNE)
+}
+
+// Does SYS at this index always exit? Only a SET of R0 just before it is trusted
+pub(crate) fn is_exit(instructions: &[(Instructions, InstructionMode, Vec<Parameter>)], index: usize) -> bool{
+    match index.checked_sub(1).map(|x| &instructions[x]){
+        Some((Instructions::SET, InstructionMode::Immediate, args)) => {
+            args[0].value as usize == SYSCALL_NUMBER_REGISTER && args.get(2).map(|x| x.value) == Some(Syscall::EXIT as u32)
+        },
+        _ => false,
+    }
+}
+
+pub fn verify(text: &[u32], text_address: u32, entry: u32) -> Vec<Issue>{
+    let mut issues = Vec::new();
+    let addresses = code_addresses(text);
+    let instructions = decode_instructions_at(text, text_address);
+
+    // The byte offset in the text of each instruction
+    let offsets: Vec<u32> = addresses.iter().enumerate().filter(|(_, x)| x.is_some()).map(|(i, _)| i as u32 * 4).collect();
+
+    for (index, (instruction, mode, args)) in instructions.iter().enumerate(){
+        let offset = offsets[index];
+        let raw = text[offset as usize / 4];
+        let mut issue = |message: String| issues.push(Issue{offset, message});
+
+        if *instruction == Instructions::UD{
+            issue(format!("Invalid opcode 0x{:02X}", raw >> 24));
+            continue;
+        }
+        if raw & 0x00200000 != 0 || (raw & 0x00100000 != 0 && !is_relative(raw)){
+            issue(format!("Invalid mode 0x{:X}", (raw & 0x00F00000) >> 20));
+        }
+
+        let control_register = match instruction{
+            Instructions::MTCR => Some(args[0].value),
+            Instructions::MFCR => Some(args[1].value),
+            _ => None,
+        };
+        if let Some(number) = control_register{
+            if ControlRegister::try_from(number as usize).is_err(){
+                issue(format!("Invalid control register {}", number));
+            }
+        }
+
+        if is_extended(raw) && offset as usize / 4 + 1 >= text.len(){
+            issue(String::from("The extension word is missing"));
+            continue;
+        }
+
+        if is_branch(*instruction) && *mode == InstructionMode::Immediate{
+            if let Some(message) = check_target(args[2].value, text_address, instructions.len()){
+                issue(format!("Jump target {}", message));
+            }
+        }
+    }
+
+    if let Some(message) = check_target(entry, text_address, instructions.len()){
+        issues.push(Issue{offset: 0, message: format!("Entry point {}", message)});
+        return issues;
+    }
+
+    // Walk every path from the entry point
+    let mut reachable = vec![false; instructions.len()];
+    let index_of = |address: u32| (address - text_address) as usize / 4; // Only for checked addresses
+    let mut queue = VecDeque::from([index_of(entry)]);
+    let mut halts = false;
+    let mut dynamic = false;
+
+    while let Some(index) = queue.pop_front(){
+        if reachable[index]{
+            continue;
+        }
+        reachable[index] = true;
+
+        let (instruction, mode, args) = &instructions[index];
+        let target = match (instruction, args.get(2)){
+            (x, Some(target)) if is_branch(*x) && *mode == InstructionMode::Immediate && check_target(target.value, text_address, instructions.len()).is_none() => {
+                Some(index_of(target.value))
+            },
+            _ => None,
+        };
+
+        let falls_through = match instruction{
+            Instructions::HLT => {
+                halts = true;
+                false
+            },
+            Instructions::SYS if is_exit(&instructions, index) => {
+                halts = true;
+                false
+            },
+            Instructions::JMP if *mode != InstructionMode::Immediate => {
+                dynamic = true;
+                false
+            },
+            Instructions::RET | Instructions::IRET => {
+                dynamic = true;
+                false
+            },
+            Instructions::JMP | Instructions::UD => false,
+            _ => true,
+        };
+
+        if let Some(target) = target{
+            queue.push_back(target);
+        }
+        if falls_through{
+            if index + 1 < instructions.len(){
+                queue.push_back(index + 1);
+            }else{
+                issues.push(Issue{offset: offsets[index], message: String::from("Execution can run past the end of the program")});
+            }
+        }
+    }
+
+    if !halts && !dynamic{
+        issues.push(Issue{offset: offsets[index_of(entry)], message: String::from("Neither HLT nor SYS EXIT is reachable from the entry point")});
+    }
+
+    issues.sort_by_key(|x| x.offset);
+    issues
+}
+
+// Describe what's wrong with a code address, if anything
+fn check_target(target: u32, text_address: u32, instructions: usize) -> Option<String>{
+    if !target.is_multiple_of(4){
+        return Some(format!("0x{:X} isn't on an instruction boundary", target));
+    }
+    if target < text_address || (target - text_address) as usize / 4 >= instructions{
+        return Some(format!("0x{:X} is outside of the program", target));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn targets_count_from_the_text_address(){
+        let text = [0x1F400001, 0x104, 0x00000000]; // JMP 0x104, HLT
+
+        assert_eq!(verify(&text, 0x100, 0x100), vec![]);
+        assert_eq!(verify(&text, 0x100, 0), vec![Issue{offset: 0, message: String::from("Entry point 0x0 is outside of the program")}]);
+        assert_eq!(verify(&text, 0, 0)[0], Issue{offset: 0, message: String::from("Jump target 0x104 is outside of the program")});
+    }
+
+    #[test]
+    fn exit_is_a_terminator(){
+        let text = [0x03400020, 0x25000000]; // SET R0, EXIT; SYS
+        assert_eq!(verify(&text, 0, 0), vec![]);
+
+        // Without the SET, the call could be anything
+        let issues = verify(&text[1..], 0, 0);
+        assert_eq!(issues.iter().map(|x| x.message.as_str()).collect::<Vec<_>>(), vec![
+            "Execution can run past the end of the program",
+            "Neither HLT nor SYS EXIT is reachable from the entry point",
+        ]);
+    }
+}
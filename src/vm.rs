@@ -1,90 +1,499 @@
 use std::path::Path;
 use std::io::Read;
 use std::fs::File;
-
-use crate::instructions::{InstructionMode, Instructions};
-use crate::registers::Registers;
-use crate::memory::Memory;
-use crate::utils::{Parameter, decode_instructions};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::instructions::{InstructionMode, Instructions, HLT_STATUS_REGISTER};
+use crate::registers::{ControlRegister, Registers};
+use crate::memory::{AlignmentPolicy, Memory};
+use crate::mmu::{Mmu, PAGE_SIZE};
+use crate::fault::Fault;
+use crate::layout::{ARGUMENTS_SIZE, HEAP_START, RNG_BASE, STACK_LIMIT, STACK_TOP, TIMER_BASE, UART_BASE};
+use crate::interrupts::*;
+use crate::debug::DebugInfo;
+use crate::devices::Bus;
+use crate::executable::Executable;
+use crate::image::{read_image, ImageFormat};
+use crate::fs::FileTable;
+use crate::signature::{self, TrustedKeys};
+use crate::devices::rng::Rng;
+use crate::devices::timer::Timer;
+use crate::devices::uart::Uart;
+use crate::syscall::{SyscallTable, SYSCALL_ARGUMENT_REGISTERS, SYSCALL_NUMBER_REGISTER, SYSCALL_RETURN_REGISTER};
+use crate::host::{HostError, HostFunction, VmContext};
+use crate::utils::{Parameter, decode_instructions_at};
 
 pub struct VirtualMachine{
     pub registers: Registers,
     pub memory: Memory,
+    pub mmu: Mmu,
+    pub devices: Bus, // Memory mapped devices, checked before Memory on every load and store
+
+    program: Arc<Vec<(Instructions, InstructionMode, Vec<Parameter>)>>, // Shared with forked VMs. The program is stored as a vector of u32, as that's the size of a FULL instruction
+
+    alignment_policy: AlignmentPolicy,
+    misaligned_accesses: u64, // Counted when the policy is Emulate
+
+    heap_break: u32, // End of the heap - see layout.rs
+
+    pub syscalls: SyscallTable, // Handlers for the SYS instruction
+    host_functions: HashMap<u32, HostFunction>, // Called by HCALL, see host.rs
+    pub files: FileTable, // Files opened by the guest, see fs.rs
+
+    rng_seed: u64, // Seed of the RNG device, so a run can be replayed
+
+    debug_info: Option<Arc<DebugInfo>>, // Symbols and source lines for the program, see debug.rs
+    trusted_keys: Option<Arc<TrustedKeys>>, // Images loaded from files must be signed by one of these, see signature.rs
+    trace: bool, // Print every instruction before it runs
 
-    program: Vec<(Instructions, InstructionMode, Vec<Parameter>)>, // The program is stored as a vector of u32, as that's the size of a FULL instruction
+    pending_interrupts: u32, // Lines raised with assert_interrupt, as a bitmask. Devices' lines are checked separately
 
     // Runtime Flags
     has_jumped: bool,
+    exit_status: Option<u32>, // Set by HLT and the EXIT system call
+}
+
+impl Default for VirtualMachine{
+    fn default() -> Self{
+        Self::new()
+    }
 }
 
 impl VirtualMachine{
     pub fn new() -> Self{
+        let mut registers = Registers::new();
+        registers.set_sp(STACK_TOP as usize);
+
+        let mut devices = Bus::new();
+        devices.attach(UART_BASE, Box::new(Uart::new()));
+        devices.attach_with_irq(TIMER_BASE, IRQ_TIMER, Box::new(Timer::new()));
+        devices.attach(RNG_BASE, Box::new(Rng::new(0)));
+
         VirtualMachine{
-            registers: Registers::new(),
+            registers,
             memory: Memory::new(),
-            program: Vec::new(),
+            mmu: Mmu::new(),
+            devices,
+            program: Arc::new(Vec::new()),
+
+            alignment_policy: AlignmentPolicy::Allow,
+            misaligned_accesses: 0,
+
+            heap_break: HEAP_START,
+
+            syscalls: SyscallTable::new(),
+            host_functions: HashMap::new(),
+            files: FileTable::new(),
+
+            rng_seed: 0,
+
+            debug_info: None,
+            trusted_keys: None,
+            trace: false,
+
+            pending_interrupts: 0,
 
             has_jumped: false,
+            exit_status: None,
+        }
+    }
+
+    // Create a copy of this VM in its current state. The child has its own registers, and shares
+    // memory pages and the program with the parent until either of them writes to a page
+    pub fn fork(&self) -> Self{
+        VirtualMachine{
+            registers: self.registers.clone(),
+            memory: self.memory.clone(),
+            mmu: Mmu::new(),
+            devices: self.devices.fork(),
+            program: Arc::clone(&self.program),
+
+            alignment_policy: self.alignment_policy,
+            misaligned_accesses: self.misaligned_accesses,
+
+            heap_break: self.heap_break,
+
+            syscalls: self.syscalls.clone(),
+            host_functions: self.host_functions.clone(),
+            files: self.files.fork(),
+
+            rng_seed: self.rng_seed,
+
+            debug_info: self.debug_info.clone(),
+            trusted_keys: self.trusted_keys.clone(),
+            trace: self.trace,
+
+            pending_interrupts: self.pending_interrupts,
+
+            has_jumped: self.has_jumped,
+            exit_status: self.exit_status,
+        }
+    }
+
+    pub fn set_alignment_policy(&mut self, policy: AlignmentPolicy){
+        self.alignment_policy = policy;
+    }
+
+    pub fn get_alignment_policy(&self) -> AlignmentPolicy{
+        self.alignment_policy
+    }
+
+    pub fn get_misaligned_accesses(&self) -> u64{
+        self.misaligned_accesses
+    }
+
+    // Register a function guest code can call with HCALL `id`, replacing any existing one
+    pub fn register_host_fn<F>(&mut self, id: u32, function: F) where F: Fn(&mut VmContext) -> Result<(), HostError> + Send + Sync + 'static{
+        self.host_functions.insert(id, Arc::new(function));
+    }
+
+    pub fn unregister_host_fn(&mut self, id: u32){
+        self.host_functions.remove(&id);
+    }
+
+    // Restart the RNG device from `seed`
+    pub fn seed_rng(&mut self, seed: u64){
+        self.rng_seed = seed;
+        self.devices.attach(RNG_BASE, Box::new(Rng::new(seed)));
+    }
+
+    pub fn get_rng_seed(&self) -> u64{
+        self.rng_seed
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo){
+        self.debug_info = Some(Arc::new(debug_info));
+    }
+
+    pub fn get_debug_info(&self) -> Option<&DebugInfo>{
+        self.debug_info.as_deref()
+    }
+
+    // Print every instruction, with its address, to stderr before it runs
+    pub fn set_trace(&mut self, trace: bool){
+        self.trace = trace;
+    }
+
+    // Describe a code address with the debug info, eg: `loop+0x8 (main.s:42)`, or just the address without it
+    pub fn symbolize(&self, address: u32) -> String{
+        match &self.debug_info{
+            Some(x) => x.symbolize_code(address),
+            None => format!("0x{:08X}", address),
         }
     }
 
+    // Describe a memory address with the debug info's data symbols, eg: `0x00002004 (counter+0x4)`
+    pub fn symbolize_data(&self, address: u32) -> String{
+        match self.debug_info.as_ref().and_then(|x| x.symbolize_data(address)){
+            Some(x) => format!("0x{:08X} ({})", address, x),
+            None => format!("0x{:08X}", address),
+        }
+    }
+
+    // Where the program is (or stopped, after a fault)
+    pub fn symbolize_pc(&self) -> String{
+        self.symbolize((self.registers.get_pc() * 4) as u32)
+    }
+
+    // Raise an interrupt line. It stays pending until the guest's handler for it is entered
+    pub fn assert_interrupt(&mut self, line: u32){
+        assert!(line < IRQ_LINES, "Invalid interrupt line: {}", line);
+        self.pending_interrupts |= 1 << line;
+    }
+
+    pub fn clear_interrupt(&mut self, line: u32){
+        self.pending_interrupts &= !(1 << line);
+    }
+
+    pub fn get_heap_break(&self) -> u32{
+        self.heap_break
+    }
+
+    // Move the end of the heap. The heap can't shrink below HEAP_START or grow into the stack
+    pub fn brk(&mut self, new_break: u32) -> Result<u32, Fault>{
+        if !(HEAP_START..=STACK_LIMIT).contains(&new_break){
+            return Err(Fault::HeapOverflow{requested: new_break});
+        }
+
+        self.heap_break = new_break;
+        Ok(self.heap_break)
+    }
+
+    // Grow (or shrink, if negative) the heap by `increment` bytes, returning the old break
+    pub fn sbrk(&mut self, increment: i32) -> Result<u32, Fault>{
+        let old_break = self.heap_break;
+        let new_break = (old_break as i64 + increment as i64).clamp(0, u32::MAX as i64) as u32;
+
+        self.brk(new_break)?;
+        Ok(old_break)
+    }
+
+    // Stop the VM after the current instruction
+    pub fn exit(&mut self, status: u32){
+        self.exit_status = Some(status);
+    }
+
+    pub fn get_exit_status(&self) -> Option<u32>{
+        self.exit_status
+    }
+
+    // Read `length` bytes from a virtual address, for host code working on guest buffers.
+    // Anything outside of physical memory is a bus error, like a load
+    pub fn read_guest_bytes(&mut self, address: u32, length: usize) -> Result<Vec<u8>, Fault>{
+        let ptbr = self.registers.get_control(ControlRegister::PTBR);
+
+        // The guest picks the length, so don't allocate more than it could have
+        if length > self.memory.size(){
+            return Err(Fault::BusError{address});
+        }
+        let mut buffer = vec![0u8; length];
+
+        // Translate once per page
+        let mut done = 0;
+        while done < length{
+            let virtual_address = address.wrapping_add(done as u32);
+            let chunk = ((PAGE_SIZE - virtual_address % PAGE_SIZE) as usize).min(length - done);
+
+            let physical = self.mmu.translate(ptbr, &self.memory, virtual_address, false)?;
+            if physical + chunk > self.memory.size(){
+                return Err(Fault::BusError{address: virtual_address});
+            }
+            self.memory.read_bytes(physical, &mut buffer[done..done + chunk]);
+            done += chunk;
+        }
+
+        Ok(buffer)
+    }
+
+    // Write `data` to a virtual address, for host code working on guest buffers
+    pub fn write_guest_bytes(&mut self, address: u32, data: &[u8]) -> Result<(), Fault>{
+        let ptbr = self.registers.get_control(ControlRegister::PTBR);
+
+        let mut done = 0;
+        while done < data.len(){
+            let virtual_address = address.wrapping_add(done as u32);
+            let chunk = ((PAGE_SIZE - virtual_address % PAGE_SIZE) as usize).min(data.len() - done);
+
+            let physical = self.mmu.translate(ptbr, &self.memory, virtual_address, true)?;
+            if physical + chunk > self.memory.size(){
+                return Err(Fault::BusError{address: virtual_address});
+            }
+            self.memory.write_bytes(physical, &data[done..done + chunk]);
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
     pub fn dump(&self){
         // print out register state
         println!("Registers:");
-        println!("PC: 0x{:04X}", self.registers.get_pc());
+        if self.debug_info.is_some(){
+            println!("PC: 0x{:04X} {}", self.registers.get_pc(), self.symbolize_pc());
+        }else{
+            println!("PC: 0x{:04X}", self.registers.get_pc());
+        }
         println!("SP: 0x{:04X}", self.registers.get_sp());
         println!("CMP: 0x{:02X}", self.registers.get_cmp_flag());
-        println!("");
+        println!("INT: 0x{:02X}", self.registers.get_interrupt_flag());
+        println!("BRK: 0x{:06X}", self.heap_break);
+        println!("FAR: {}", self.symbolize_data(self.registers.get_control(ControlRegister::FAR)));
+        println!("RNG seed: {}", self.rng_seed);
+        if self.alignment_policy == AlignmentPolicy::Emulate{
+            println!("Misaligned accesses: {}", self.misaligned_accesses);
+        }
+        println!();
         for i in 0..self.registers.registers.len(){
             println!("R{}: 0x{:08X}", i, self.registers.get_register(i));
         }
 
-        println!("");
-        // output the result of the data stored in reg 3
+        println!();
         println!("Data:");
-        println!("{:?}", self.memory.get_memory(0x2000));
-        println!("{:?}", self.memory.get_memory(0x2100));
+        for line in self.data_dump(){
+            println!("{}", line);
+        }
+    }
+
+    // The value of every variable in the debug info. Without any, the two words the sample program writes
+    fn data_dump(&self) -> Vec<String>{
+        let mut addresses: Vec<u32> = self.debug_info.iter().flat_map(|x| x.data_symbols().map(|(address, _)| address)).collect();
+        if addresses.is_empty(){
+            addresses = vec![0x2000, 0x2100];
+        }
+
+        addresses.into_iter()
+            .filter(|x| *x as usize + 4 <= self.memory.size())
+            .map(|x| format!("{}: 0x{:08X}", self.symbolize_data(x), self.memory.get_memory(x as usize)))
+            .collect()
+    }
+
+    // Load an executable - see executable.rs
+    pub fn load_program<T>(&mut self, file_path: &T) -> Result<(), String> where T: AsRef<Path> + ?Sized{
+        self.load_image(file_path, ImageFormat::Dbv)
     }
 
-    pub fn load_program<T>(&mut self, file_path: &T) -> Result<(), &'static str> where T: AsRef<Path> + ?Sized{
-        let mut file = File::open(file_path).unwrap();
-        let mut file_buffer = Vec::new();
-        file.read_to_end(&mut file_buffer).unwrap();
+    // Load a headerless file of instructions, starting at code address 0
+    pub fn load_raw_program<T>(&mut self, file_path: &T) -> Result<(), String> where T: AsRef<Path> + ?Sized{
+        self.load_image(file_path, ImageFormat::Raw)
+    }
+
+    // Load a program in any format - see image.rs
+    pub fn load_image<T>(&mut self, file_path: &T, format: ImageFormat) -> Result<(), String> where T: AsRef<Path> + ?Sized{
+        self.load_image_at(file_path, format, 0)
+    }
 
-        let mut program = Vec::new();
-        // Read the file in 32 bit chunks, stored big endian
-        for i in 0..file_buffer.len() / 4{
-            let mut instruction: u32 = 0;
-            for j in 0..4{
-                instruction |= (file_buffer[i * 4 + j] as u32) << (24 - j * 8);
+    // Load a program in any format, moved by `base` - see executable.rs
+    pub fn load_image_at<T>(&mut self, file_path: &T, format: ImageFormat, base: u32) -> Result<(), String> where T: AsRef<Path> + ?Sized{
+        let executable = self.read_image_file(file_path, format)?;
+        self.load_executable_at(&executable, base).map_err(String::from)
+    }
+
+    // Load a library in any format - see load_library
+    pub fn load_library_image<T>(&mut self, file_path: &T, format: ImageFormat, base: u32) -> Result<(), String> where T: AsRef<Path> + ?Sized{
+        let executable = self.read_image_file(file_path, format)?;
+        self.load_library(&executable, base).map_err(String::from)
+    }
+
+    // Only accept images signed by a trusted key from now on
+    pub fn set_trusted_keys(&mut self, keys: TrustedKeys){
+        self.trusted_keys = Some(Arc::new(keys));
+    }
+
+    fn read_image_file<T>(&self, file_path: &T, format: ImageFormat) -> Result<Executable, String> where T: AsRef<Path> + ?Sized{
+        let bytes = read_program_file(file_path)?;
+
+        if let Some(keys) = &self.trusted_keys{
+            // Only .dbv files have somewhere to keep a signature
+            if format != ImageFormat::Dbv{
+                return Err(String::from("Only signed .dbv images can be loaded"));
             }
-            program.push(instruction);
+            let (_, trailer) = signature::check(&bytes)?;
+            keys.verify(trailer.as_ref())?;
         }
 
-        let program = decode_instructions(&program);
+        read_image(&bytes, format)
+    }
+
+    pub fn load_executable(&mut self, executable: &Executable) -> Result<(), &'static str>{
+        self.load_executable_at(executable, 0)
+    }
+
+    // Replace the program with a relocatable executable moved by `base`, and start at its entry point
+    pub fn load_executable_at(&mut self, executable: &Executable, base: u32) -> Result<(), &'static str>{
+        let executable = executable.relocate(base)?;
+
+        self.program = Arc::new(Vec::new());
+        self.place(&executable);
+        self.registers.set_pc((executable.entry / 4) as usize);
+
+        Ok(())
+    }
+
+    // Add a relocatable executable, moved by `base`, to the loaded program without running it.
+    // It replaces any code and data already at its addresses, so pick a base clear of the program.
+    // Its entry point is ignored - the program calls into it at addresses it's been told
+    pub fn load_library(&mut self, executable: &Executable, base: u32) -> Result<(), &'static str>{
+        let executable = executable.relocate(base)?;
+        self.place(&executable);
+
+        Ok(())
+    }
+
+    // Copy an executable's text into the program at its code address, and its data into memory.
+    // Gaps in the program are filled with UD, so jumping into them raises an invalid opcode exception
+    fn place(&mut self, executable: &Executable){
+        self.memory.write_bytes(executable.data_address as usize, &executable.data);
+        self.memory.fill(executable.bss_address as usize, executable.bss_size as usize, 0);
 
-        self.program = program;
+        let instructions = decode_instructions_at(&executable.text, executable.text_address);
 
-        for i in 0..self.program.len(){
-            println!("{:?}", self.program[i]);
+        let start = executable.text_address as usize / 4;
+        let end = start + instructions.len();
+        let program = Arc::make_mut(&mut self.program);
+        if program.len() < end{
+            program.resize(end, (Instructions::UD, InstructionMode::Register, vec![Parameter{value: Instructions::UD as u32}]));
+        }
+        program.splice(start..end, instructions);
+    }
+
+    // Copy the program's arguments and environment (NAME=VALUE strings) to the top of the stack,
+    // and point R1, R2 and R3 at them - see layout.rs. Call it before the program runs
+    pub fn set_arguments(&mut self, arguments: &[String], environment: &[String]) -> Result<(), &'static str>{
+        let strings_size: usize = arguments.iter().chain(environment).map(|x| x.len() + 1).sum();
+        let strings_size = strings_size.next_multiple_of(4);
+        let pointers_size = (arguments.len() + 1 + environment.len() + 1) * 4;
+        if strings_size + pointers_size > ARGUMENTS_SIZE as usize{
+            return Err("The arguments and environment don't fit on the stack");
+        }
+
+        let strings_start = STACK_TOP - strings_size as u32;
+        let envp = strings_start - (environment.len() as u32 + 1) * 4;
+        let argv = envp - (arguments.len() as u32 + 1) * 4;
+
+        // The pointers are written in order, each one followed by its terminating 0
+        let mut pointer = argv;
+        let mut string = strings_start;
+        for list in [arguments, environment]{
+            for x in list{
+                self.memory.write::<u32>(pointer as usize, string);
+                self.memory.write_bytes(string as usize, x.as_bytes());
+                self.memory.write::<u8>(string as usize + x.len(), 0);
+
+                pointer += 4;
+                string += x.len() as u32 + 1;
+            }
+            self.memory.write::<u32>(pointer as usize, 0);
+            pointer += 4;
         }
+        self.memory.fill(string as usize, (STACK_TOP - string) as usize, 0);
+
+        self.registers.set_register(1, arguments.len() as u32);
+        self.registers.set_register(2, argv);
+        self.registers.set_register(3, envp);
+        self.registers.set_sp(argv as usize);
 
         Ok(())
     }
 
-    fn fetch(&self) -> (Instructions, InstructionMode, Vec<Parameter>){
-        let (instruction, mode, args) = &self.program[self.registers.get_pc()];
+    // Get the instruction at the PC. Jumping past the end of the program is a bus error, at its code address
+    fn fetch(&self) -> Result<(Instructions, InstructionMode, Vec<Parameter>), Fault>{
+        let pc = self.registers.get_pc();
+        let (instruction, mode, args) = self.program.get(pc).ok_or(Fault::BusError{address: (pc * 4) as u32})?;
         // clone and return
-        (instruction.clone(), mode.clone(), args.clone())
+        Ok((*instruction, *mode, args.clone()))
     }
 
-    pub fn run(&mut self) -> Result<(), &'static str>{
+    // Run until the program halts, returning its exit status
+    pub fn run(&mut self) -> Result<u32, Fault>{
 
         // Run the program
         'running: loop {
+            // Take the highest priority pending interrupt, if they're enabled
+            if self.registers.get_interrupt_flag() & INTERRUPT_ENABLE != 0{
+                self.take_interrupt()?;
+            }
+
             // Get the instruction
-            let (instruction, mode, args) = self.fetch();
-            if self.execute(instruction, mode, args){
+            let result = match self.fetch(){
+                Ok((instruction, mode, args)) => {
+                    if self.trace{
+                        eprintln!("{}: {:?} {:?} {:?}", self.symbolize_pc(), instruction, mode, args.iter().map(|x| x.value).collect::<Vec<_>>());
+                    }
+                    self.execute(instruction, mode, args)
+                },
+                Err(fault) => Err(fault),
+            };
+            match result{
+                Ok(true) => break 'running,
+                Ok(false) => {},
+                Err(fault) => self.raise(fault)?,
+            }
+
+            // Virtual time advances by one per instruction
+            self.devices.tick(&mut self.memory);
+
+            if self.exit_status.is_some(){
                 break 'running;
             }
 
@@ -96,16 +505,337 @@ impl VirtualMachine{
             }
         }
 
+        Ok(self.exit_status.unwrap_or(0))
+    }
+
+    // Deliver an exception to the guest's handler. The faulting instruction is restarted
+    // when the handler returns with IRET. If there's no handler, the fault stops the VM
+    fn raise(&mut self, fault: Fault) -> Result<(), Fault>{
+        let vector = match fault{
+            Fault::InvalidOpcode{..} => VECTOR_INVALID_OPCODE,
+            Fault::DivideByZero => VECTOR_DIVIDE_BY_ZERO,
+            Fault::PageFault{address, ..} => {
+                self.registers.set_control(ControlRegister::FAR, address);
+                VECTOR_PAGE_FAULT
+            },
+            Fault::Misaligned{address, ..} => {
+                self.registers.set_control(ControlRegister::FAR, address);
+                VECTOR_MISALIGNED
+            },
+            Fault::BusError{address} => {
+                self.registers.set_control(ControlRegister::FAR, address);
+                VECTOR_BUS_ERROR
+            },
+            // These aren't exceptions, they always stop the VM
+            Fault::HeapOverflow{..} | Fault::InvalidSyscall{..} | Fault::UnknownHostFunction{..} | Fault::HostFunction{..} => return Err(fault),
+        };
+
+        let handler = self.get_vector(vector);
+        if handler == 0{
+            return Err(fault);
+        }
+
+        // PCs are stored as byte addresses, so they can be jumped to
+        self.registers.set_control(ControlRegister::EPC, (self.registers.get_pc() * 4) as u32);
+
+        // If the state can't be saved (eg: the stack isn't mapped), there's nothing the guest can do
+        if self.enter_handler(handler).is_err(){
+            return Err(fault);
+        }
+        self.has_jumped = true;
+
+        Ok(())
+    }
+
+    // Enter the handler of the highest priority pending interrupt line that has one
+    fn take_interrupt(&mut self) -> Result<(), Fault>{
+        let pending = self.pending_interrupts | self.devices.pending_interrupts();
+
+        for line in 0..IRQ_LINES{
+            if pending & (1 << line) == 0{
+                continue;
+            }
+
+            let handler = self.get_vector(VECTOR_IRQ_BASE + line);
+            if handler == 0{
+                continue;
+            }
+
+            self.enter_handler(handler)?;
+            self.clear_interrupt(line);
+            break;
+        }
+
+        Ok(())
+    }
+
+    // Read a handler address from the interrupt vector table. Returns 0 (no handler) if there's no table (IVT is 0),
+    // the entry is outside of memory, or it points outside of the program
+    fn get_vector(&self, vector: u32) -> u32{
+        let ivt = self.registers.get_control(ControlRegister::IVT);
+        if ivt == 0{
+            return 0;
+        }
+
+        let address = ivt as usize + (vector * 4) as usize;
+        if address + 4 > self.memory.size(){
+            return 0;
+        }
+
+        let handler = self.memory.get_memory(address);
+        if (handler / 4) as usize >= self.program.len(){
+            return 0;
+        }
+
+        handler
+    }
+
+    // Save the PC and flags to the stack, disable interrupts and jump to `handler`
+    fn enter_handler(&mut self, handler: u32) -> Result<(), Fault>{
+        self.push((self.registers.get_pc() * 4) as u32)?;
+        self.push(self.registers.get_flags())?;
+
+        let interrupt_flag = self.registers.get_interrupt_flag();
+        self.registers.set_interrupt_flag(interrupt_flag & !INTERRUPT_ENABLE);
+
+        self.registers.set_pc((handler / 4) as usize);
+
         Ok(())
     }
 
+    // The stack grows down, and SP points at the last value pushed
+    fn push(&mut self, value: u32) -> Result<(), Fault>{
+        let sp = (self.registers.get_sp() as u32).wrapping_sub(4);
+        self.store(sp, 4, value)?;
+        self.registers.set_sp(sp as usize);
+
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u32, Fault>{
+        let sp = self.registers.get_sp() as u32;
+        let value = self.load(sp, 4)?;
+        self.registers.set_sp(sp.wrapping_add(4) as usize);
+
+        Ok(value)
+    }
+
+    // Load `width` bytes (1, 2 or 4) from a virtual address, little endian
+    fn load(&mut self, address: u32, width: u32) -> Result<u32, Fault>{
+        let emulated = self.check_alignment(address, width)?;
+        let ptbr = self.registers.get_control(ControlRegister::PTBR);
+
+        if emulated || (address % PAGE_SIZE) + width > PAGE_SIZE{
+            // Access byte by byte - the access may cross a page boundary, so each byte may live in a different frame
+            let mut value: u32 = 0;
+            for i in 0..width{
+                let physical = self.mmu.translate(ptbr, &self.memory, address.wrapping_add(i), false)?;
+                value |= self.read_physical(physical, 1)? << (i * 8);
+            }
+            return Ok(value);
+        }
+
+        let physical = self.mmu.translate(ptbr, &self.memory, address, false)?;
+        self.read_physical(physical, width)
+    }
+
+    // Store the low `width` bytes (1, 2 or 4) of `value` to a virtual address, little endian
+    fn store(&mut self, address: u32, width: u32, value: u32) -> Result<(), Fault>{
+        let emulated = self.check_alignment(address, width)?;
+        let ptbr = self.registers.get_control(ControlRegister::PTBR);
+
+        if emulated || (address % PAGE_SIZE) + width > PAGE_SIZE{
+            // Translate every byte before writing any, so a fault doesn't leave a partial store
+            let mut physical = [0usize; 4];
+            for i in 0..width{
+                physical[i as usize] = self.mmu.translate(ptbr, &self.memory, address.wrapping_add(i), true)?;
+                self.check_physical(physical[i as usize], 1)?;
+            }
+            for i in 0..width{
+                self.write_physical(physical[i as usize], 1, value >> (i * 8))?;
+            }
+            return Ok(());
+        }
+
+        let physical = self.mmu.translate(ptbr, &self.memory, address, true)?;
+        self.write_physical(physical, width, value)
+    }
+
+    // Accesses have to land on a device, or inside of memory
+    fn check_physical(&mut self, physical: usize, width: u32) -> Result<(), Fault>{
+        if self.devices.find(physical).is_none() && physical + width as usize > self.memory.size(){
+            return Err(Fault::BusError{address: physical as u32});
+        }
+
+        Ok(())
+    }
+
+    // Read from a device if one is mapped at the physical address, otherwise from Memory
+    fn read_physical(&mut self, physical: usize, width: u32) -> Result<u32, Fault>{
+        if let Some((device, offset)) = self.devices.find(physical){
+            return Ok(device.read(offset, width));
+        }
+
+        self.check_physical(physical, width)?;
+        Ok(match width{
+            1 => self.memory.get_memory_u8(physical),
+            2 => self.memory.get_memory_u16(physical),
+            _ => self.memory.get_memory(physical),
+        })
+    }
+
+    fn write_physical(&mut self, physical: usize, width: u32, value: u32) -> Result<(), Fault>{
+        if let Some((device, offset)) = self.devices.find(physical){
+            device.write(offset, width, value);
+            return Ok(());
+        }
+
+        self.check_physical(physical, width)?;
+        match width{
+            1 => self.memory.set_memory_u8(physical, value),
+            2 => self.memory.set_memory_u16(physical, value),
+            _ => self.memory.set_memory(physical, value),
+        }
+
+        Ok(())
+    }
+
+    // The destination register and both source values of an arithmetic instruction, following the same
+    // modes as ADD: b is a register, an immediate, or read from the address in a register (plus an offset)
+    fn arithmetic_operands(&mut self, mode: InstructionMode, args: &[Parameter]) -> Result<(u32, u32, u32), Fault>{
+        let destination_register = args[0].get_value(&self.registers, &self.memory);
+
+        let a_register = args[1].get_value(&self.registers, &self.memory);
+        let a_value = self.registers.get_register(a_register as usize);
+
+        let b_value = match mode{
+            InstructionMode::Register => {
+                let b_register = args[2].get_value(&self.registers, &self.memory);
+                self.registers.get_register(b_register as usize)
+            },
+            InstructionMode::Immediate => args[2].get_value(&self.registers, &self.memory),
+            InstructionMode::RegisterIndirect => {
+                let b_register = args[2].get_value(&self.registers, &self.memory);
+                let b_address = self.registers.get_register(b_register as usize);
+
+                self.load(b_address, 4)?
+            },
+            InstructionMode::BaseOffset => {
+                let b_register = args[2].get_value(&self.registers, &self.memory);
+                let b_address = self.registers.get_register(b_register as usize);
+
+                let offset = args[3].get_value(&self.registers, &self.memory);
+
+                self.load(b_address.wrapping_add(offset), 4)?
+            },
+        };
+
+        Ok((destination_register, a_value, b_value))
+    }
+
+    // Apply the alignment policy to an access. Returns true if the access has to be emulated with byte accesses
+    fn check_alignment(&mut self, address: u32, width: u32) -> Result<bool, Fault>{
+        if address.is_multiple_of(width){
+            return Ok(false);
+        }
+
+        match self.alignment_policy{
+            AlignmentPolicy::Allow => Ok(false),
+            AlignmentPolicy::Fault => Err(Fault::Misaligned{address, width}),
+            AlignmentPolicy::Emulate => {
+                self.misaligned_accesses += 1;
+                Ok(true)
+            }
+        }
+    }
+
+    // The memory address of a load or store. Loads and stores share their operands, so
+    // `SD R1, <address>` followed by `LD R1, <address>` gets back what was stored
+    fn data_address(&self, mode: InstructionMode, args: &[Parameter]) -> u32{
+        match mode{
+            InstructionMode::Register | InstructionMode::RegisterIndirect => {
+                let source_register = args[1].get_value(&self.registers, &self.memory);
+                self.registers.get_register(source_register as usize)
+            },
+            InstructionMode::Immediate => {
+                // Absolute address
+                args[2].get_value(&self.registers, &self.memory)
+            },
+            InstructionMode::BaseOffset => {
+                let source_register = args[1].get_value(&self.registers, &self.memory);
+                let address = self.registers.get_register(source_register as usize);
+
+                let offset = args[3].get_value(&self.registers, &self.memory);
+
+                address.wrapping_add(offset)
+            },
+        }
+    }
+
+    // Shared by SD, SD16 and SD8. The first argument is the register holding the value to store
+    fn store_data(&mut self, mode: InstructionMode, args: &[Parameter], width: u32) -> Result<(), Fault>{
+        let source_register = args[0].get_value(&self.registers, &self.memory);
+        let value = self.registers.get_register(source_register as usize);
+
+        let address = self.data_address(mode, args);
+        self.store(address, width, value)
+    }
+
+    // Shared by LD, LD16, LD8, LD16S and LD8S. The first argument is the destination register
+    fn load_data(&mut self, mode: InstructionMode, args: &[Parameter], width: u32, signed: bool) -> Result<(), Fault>{
+        let destination_register = args[0].get_value(&self.registers, &self.memory);
+
+        let address = self.data_address(mode, args);
+        let value = self.load(address, width)?;
+        let value = match (signed, width){
+            (true, 1) => value as u8 as i8 as i32 as u32,
+            (true, 2) => value as u16 as i16 as i32 as u32,
+            _ => value,
+        };
 
-    fn execute(&mut self, opcode: Instructions, mode: InstructionMode, args: Vec<Parameter>) -> bool{
+        self.registers.set_register(destination_register as usize, value);
+
+        Ok(())
+    }
+
+    fn execute(&mut self, opcode: Instructions, mode: InstructionMode, args: Vec<Parameter>) -> Result<bool, Fault>{
         match opcode{
-            Instructions::HLT => return true,
+            Instructions::HLT => {
+                // A plain HLT exits with 0. The status is the value in immediate mode, or in the
+                // register given when the encoding asks for it
+                let status = match mode{
+                    InstructionMode::Immediate => args[2].get_value(&self.registers, &self.memory),
+                    InstructionMode::Register if args[3].value & HLT_STATUS_REGISTER != 0 => {
+                        let register = args[0].get_value(&self.registers, &self.memory);
+                        self.registers.get_register(register as usize)
+                    },
+                    _ => 0,
+                };
+
+                self.exit_status = Some(status);
+                return Ok(true);
+            },
 
-            Instructions::PSH => {}
-            Instructions::POP => {}
+            Instructions::PSH => {
+                let value = match mode{
+                    InstructionMode::Immediate => args[2].get_value(&self.registers, &self.memory),
+                    _ => {
+                        let source_register = args[0].get_value(&self.registers, &self.memory);
+                        self.registers.get_register(source_register as usize)
+                    },
+                };
+
+                self.push(value)?;
+            }
+            Instructions::POP => {
+                let value = self.pop()?;
+
+                // Only register mode keeps the value, the rest discard it
+                if mode == InstructionMode::Register{
+                    let destination_register = args[0].get_value(&self.registers, &self.memory);
+                    self.registers.set_register(destination_register as usize, value);
+                }
+            }
             Instructions::SET => {
                 match mode{
                     InstructionMode::Register => {
@@ -132,7 +862,7 @@ impl VirtualMachine{
                         // Get the address from the register
                         let address = self.registers.get_register(source_register as usize);
                         // Load the value from memory
-                        let value = self.memory.get_memory(address as usize);
+                        let value = self.load(address, 4)?;
 
                         self.registers.set_register(destination_register as usize, value);
                     },
@@ -143,9 +873,9 @@ impl VirtualMachine{
                         let address = self.registers.get_register(source_register as usize);
                         let offset = args[2].get_value(&self.registers, &self.memory);
 
-                        let address = address + offset;
+                        let address = address.wrapping_add(offset);
 
-                        let value = self.memory.get_memory(address as usize);
+                        let value = self.load(address, 4)?;
 
                         self.registers.set_register(destination_register as usize, value);
                     },
@@ -173,7 +903,7 @@ impl VirtualMachine{
                         let b_register = args[2].get_value(&self.registers, &self.memory);
                         let b_value = self.registers.get_register(b_register as usize);
 
-                        self.registers.set_register(destination_register as usize, a_value + b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_add(b_value));
                     },
                     InstructionMode::Immediate => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -183,7 +913,7 @@ impl VirtualMachine{
 
                         let b_value = args[2].get_value(&self.registers, &self.memory);
 
-                        self.registers.set_register(destination_register as usize, a_value + b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_add(b_value));
                     },
                     InstructionMode::RegisterIndirect => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -194,9 +924,9 @@ impl VirtualMachine{
                         let b_register = args[2].get_value(&self.registers, &self.memory);
                         let b_address = self.registers.get_register(b_register as usize);
 
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_value = self.load(b_address, 4)?;
 
-                        self.registers.set_register(destination_register as usize, a_value + b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_add(b_value));
                     },
                     InstructionMode::BaseOffset => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -209,10 +939,10 @@ impl VirtualMachine{
 
                         let offset = args[3].get_value(&self.registers, &self.memory);
 
-                        let b_address = b_address + offset;
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_address = b_address.wrapping_add(offset);
+                        let b_value = self.load(b_address, 4)?;
 
-                        self.registers.set_register(destination_register as usize, a_value + b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_add(b_value));
                     }
                 }
             }
@@ -228,7 +958,7 @@ impl VirtualMachine{
                         let b_register = args[2].get_value(&self.registers, &self.memory);
                         let b_value = self.registers.get_register(b_register as usize);
 
-                        self.registers.set_register(destination_register as usize, a_value - b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_sub(b_value));
                     },
                     InstructionMode::Immediate => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -238,7 +968,7 @@ impl VirtualMachine{
 
                         let b_value = args[2].get_value(&self.registers, &self.memory);
 
-                        self.registers.set_register(destination_register as usize, a_value - b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_sub(b_value));
                     },
                     InstructionMode::RegisterIndirect => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -249,9 +979,9 @@ impl VirtualMachine{
                         let b_register = args[2].get_value(&self.registers, &self.memory);
                         let b_address = self.registers.get_register(b_register as usize);
 
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_value = self.load(b_address, 4)?;
 
-                        self.registers.set_register(destination_register as usize, a_value - b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_sub(b_value));
                     },
                     InstructionMode::BaseOffset => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -264,10 +994,10 @@ impl VirtualMachine{
 
                         let offset = args[3].get_value(&self.registers, &self.memory);
 
-                        let b_address = b_address + offset;
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_address = b_address.wrapping_add(offset);
+                        let b_value = self.load(b_address, 4)?;
 
-                        self.registers.set_register(destination_register as usize, a_value - b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_sub(b_value));
                     }
                 }
             }
@@ -283,7 +1013,7 @@ impl VirtualMachine{
                         let b_register = args[2].get_value(&self.registers, &self.memory);
                         let b_value = self.registers.get_register(b_register as usize);
 
-                        self.registers.set_register(destination_register as usize, a_value * b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_mul(b_value));
                     },
                     InstructionMode::Immediate => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -293,7 +1023,7 @@ impl VirtualMachine{
 
                         let b_value = args[2].get_value(&self.registers, &self.memory);
 
-                        self.registers.set_register(destination_register as usize, a_value * b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_mul(b_value));
                     },
                     InstructionMode::RegisterIndirect => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -304,9 +1034,9 @@ impl VirtualMachine{
                         let b_register = args[2].get_value(&self.registers, &self.memory);
                         let b_address = self.registers.get_register(b_register as usize);
 
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_value = self.load(b_address, 4)?;
 
-                        self.registers.set_register(destination_register as usize, a_value * b_value);
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_mul(b_value));
                     },
                     InstructionMode::BaseOffset => {
                         let destination_register = args[0].get_value(&self.registers, &self.memory);
@@ -319,14 +1049,21 @@ impl VirtualMachine{
 
                         let offset = args[3].get_value(&self.registers, &self.memory);
 
-                        let b_address = b_address + offset;
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_address = b_address.wrapping_add(offset);
+                        let b_value = self.load(b_address, 4)?;
 
-                        self.registers.set_register(destination_register as usize, a_value * b_value);                        
+                        self.registers.set_register(destination_register as usize, a_value.wrapping_mul(b_value));                        
                     },
                 }
             }
-            Instructions::DIV => {}
+            Instructions::DIV => {
+                let (destination_register, a_value, b_value) = self.arithmetic_operands(mode, &args)?;
+                if b_value == 0{
+                    return Err(Fault::DivideByZero);
+                }
+
+                self.registers.set_register(destination_register as usize, a_value / b_value);
+            }
 
             Instructions::AND => {
                 match mode{
@@ -361,7 +1098,7 @@ impl VirtualMachine{
                         let b_register = args[2].get_value(&self.registers, &self.memory);
                         let b_address = self.registers.get_register(b_register as usize);
 
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_value = self.load(b_address, 4)?;
 
                         self.registers.set_register(destination_register as usize, a_value & b_value);
                     },
@@ -376,8 +1113,8 @@ impl VirtualMachine{
 
                         let offset = args[3].get_value(&self.registers, &self.memory);
 
-                        let b_address = b_address + offset;
-                        let b_value = self.memory.get_memory(b_address as usize);
+                        let b_address = b_address.wrapping_add(offset);
+                        let b_value = self.load(b_address, 4)?;
 
                         self.registers.set_register(destination_register as usize, a_value & b_value);
                     },
@@ -387,67 +1124,26 @@ impl VirtualMachine{
             Instructions::XOR => {}
             Instructions::NOT => {}
 
-            Instructions::MOD => {}
+            Instructions::MOD => {
+                let (destination_register, a_value, b_value) = self.arithmetic_operands(mode, &args)?;
+                if b_value == 0{
+                    return Err(Fault::DivideByZero);
+                }
+
+                self.registers.set_register(destination_register as usize, a_value % b_value);
+            }
 
             Instructions::SL => {}
             Instructions::SR => {}
 
-            Instructions::SD => {
-                match mode{
-                    InstructionMode::Register => {
-                        println!("Invalid mode for SD");
-                        let source_register = args[0].get_value(&self.registers, &self.memory);
-                        let source_value = self.registers.get_register(source_register as usize);
-
-                        // Get the value from the register
-                        let destination_register = args[1].get_value(&self.registers, &self.memory);
-                        let destination_value = self.registers.get_register(destination_register as usize);
-
-                        self.memory.set_memory(source_value as usize, destination_value);
-                    },
-                    InstructionMode::Immediate => {
-                        let source_register = args[0].get_value(&self.registers, &self.memory);
-                        let source_value = self.registers.get_register(source_register as usize);
-
-                        // The value just is the value
-                        let destination_value = args[1].get_value(&self.registers, &self.memory);
-
-                        self.memory.set_memory(source_value as usize, destination_value);
-                    }, 
-                    InstructionMode::RegisterIndirect => {
-                        let source_register = args[0].get_value(&self.registers, &self.memory);
-                        let source_value = self.registers.get_register(source_register as usize);
-
-                        let destination_register = args[1].get_value(&self.registers, &self.memory);
-                        let destination_address = self.registers.get_register(destination_register as usize);
-                        let destination_value = self.memory.get_memory(destination_address as usize);
-
-                        self.memory.set_memory(source_value as usize, destination_value);
-                    },
-                    InstructionMode::BaseOffset => {
-                        let source_register = args[0].get_value(&self.registers, &self.memory);
-                        let source_value = self.registers.get_register(source_register as usize);
-
-                        let destination_register = args[1].get_value(&self.registers, &self.memory);
-                        let destination_address = self.registers.get_register(destination_register as usize);
-
-                        let offset = args[2].get_value(&self.registers, &self.memory);
-
-                        let destination_address = destination_address + offset;
-
-                        let destination_value = self.memory.get_memory(destination_address as usize);
-
-                        self.memory.set_memory(source_value as usize, destination_value);
-                    }                    
-                }
-            }
-            Instructions::LD => {}
-            Instructions::SD16 => {}
-            Instructions::LD16 => {}
-            Instructions::SD8 => {}
-            Instructions::LD8 => {}
-            Instructions::LD16S => {}
-            Instructions::LD8S => {}
+            Instructions::SD => self.store_data(mode, &args, 4)?,
+            Instructions::LD => self.load_data(mode, &args, 4, false)?,
+            Instructions::SD16 => self.store_data(mode, &args, 2)?,
+            Instructions::LD16 => self.load_data(mode, &args, 2, false)?,
+            Instructions::SD8 => self.store_data(mode, &args, 1)?,
+            Instructions::LD8 => self.load_data(mode, &args, 1, false)?,
+            Instructions::LD16S => self.load_data(mode, &args, 2, true)?,
+            Instructions::LD8S => self.load_data(mode, &args, 1, true)?,
 
             Instructions::CMP => {
                 let register_a = args[0].get_value(&self.registers, &self.memory);
@@ -516,7 +1212,14 @@ impl VirtualMachine{
             Instructions::IFNE => {}
 
             Instructions::JMP => {
-                let address = args[2].get_value(&self.registers, &self.memory);
+                let address = match mode{
+                    // Jump to the address held in a register (eg: returning to EPC from a fault handler)
+                    InstructionMode::Register => {
+                        let register = args[0].get_value(&self.registers, &self.memory);
+                        self.registers.get_register(register as usize)
+                    },
+                    _ => args[2].get_value(&self.registers, &self.memory),
+                };
 
                 // Something to note: we need to divide this number by 4,
                 // as the program is stored as u8, but we've compacted each instruction into a u32
@@ -526,10 +1229,729 @@ impl VirtualMachine{
 
                 self.has_jumped = true;
             }
-            Instructions::CALL => {}
-            Instructions::RET => {}
+            Instructions::CALL => {
+                let address = match mode{
+                    // Call the address held in a register (eg: a function pointer)
+                    InstructionMode::Register => {
+                        let register = args[0].get_value(&self.registers, &self.memory);
+                        self.registers.get_register(register as usize)
+                    },
+                    _ => args[2].get_value(&self.registers, &self.memory),
+                };
+
+                // Return to the next instruction. PCs are stored as byte addresses, like EPC
+                self.push(((self.registers.get_pc() + 1) * 4) as u32)?;
+                self.registers.set_pc((address / 4) as usize);
+
+                self.has_jumped = true;
+            }
+            Instructions::RET => {
+                let address = self.pop()?;
+                self.registers.set_pc((address / 4) as usize);
+
+                self.has_jumped = true;
+            }
+
+            Instructions::MTCR => {
+                // A control register that doesn't exist makes the whole instruction invalid
+                let control_register = args[0].get_value(&self.registers, &self.memory);
+                let control_register = ControlRegister::try_from(control_register as usize)
+                    .map_err(|_| Fault::InvalidOpcode{opcode: Instructions::MTCR as u32})?;
+
+                let source_register = args[1].get_value(&self.registers, &self.memory);
+                let value = self.registers.get_register(source_register as usize);
+
+                self.registers.set_control(control_register, value);
+
+                // A new page table invalidates every cached translation
+                if control_register == ControlRegister::PTBR{
+                    self.mmu.flush();
+                }
+            }
+            Instructions::MFCR => {
+                let destination_register = args[0].get_value(&self.registers, &self.memory);
+
+                let control_register = args[1].get_value(&self.registers, &self.memory);
+                let control_register = ControlRegister::try_from(control_register as usize)
+                    .map_err(|_| Fault::InvalidOpcode{opcode: Instructions::MFCR as u32})?;
+
+                let value = self.registers.get_control(control_register);
+                self.registers.set_register(destination_register as usize, value);
+            }
+            Instructions::TLBF => {
+                self.mmu.flush();
+            }
+
+            Instructions::EI => {
+                let interrupt_flag = self.registers.get_interrupt_flag();
+                self.registers.set_interrupt_flag(interrupt_flag | INTERRUPT_ENABLE);
+            }
+            Instructions::DI => {
+                let interrupt_flag = self.registers.get_interrupt_flag();
+                self.registers.set_interrupt_flag(interrupt_flag & !INTERRUPT_ENABLE);
+            }
+            Instructions::IRET => {
+                let flags = self.pop()?;
+                let address = self.pop()?;
+
+                self.registers.set_flags(flags);
+                self.registers.set_pc((address / 4) as usize);
+
+                self.has_jumped = true;
+            }
+            Instructions::HCALL => {
+                let id = match mode{
+                    InstructionMode::Register => {
+                        let register = args[0].get_value(&self.registers, &self.memory);
+                        self.registers.get_register(register as usize)
+                    },
+                    _ => args[2].get_value(&self.registers, &self.memory),
+                };
+
+                let function = match self.host_functions.get(&id){
+                    Some(x) => Arc::clone(x),
+                    None => return Err(Fault::UnknownHostFunction{id}),
+                };
+
+                let mut context = VmContext{
+                    registers: &mut self.registers,
+                    memory: &mut self.memory,
+                };
+
+                if let Err(e) = function(&mut context){
+                    let pc = (self.registers.get_pc() * 4) as u32;
+                    return Err(Fault::HostFunction{id, pc, message: e.to_string()});
+                }
+            }
+            Instructions::UD => {
+                // The decoder stores the raw opcode as the only argument
+                let opcode = args[0].get_value(&self.registers, &self.memory);
+                return Err(Fault::InvalidOpcode{opcode});
+            }
+
+            Instructions::SYS => {
+                let number = self.registers.get_register(SYSCALL_NUMBER_REGISTER);
+                let handler = match self.syscalls.get(number){
+                    Some(x) => x,
+                    None => return Err(Fault::InvalidSyscall{number}),
+                };
+
+                let args = SYSCALL_ARGUMENT_REGISTERS.map(|register| self.registers.get_register(register));
+                let result = handler(self, args)?;
+
+                self.registers.set_register(SYSCALL_RETURN_REGISTER, result);
+            }
+        };
+
+        Ok(false)
+    }
+}
+
+fn read_program_file<T>(file_path: &T) -> Result<Vec<u8>, &'static str> where T: AsRef<Path> + ?Sized{
+    let mut file = File::open(file_path).map_err(|_| "Couldn't open the program file")?;
+    let mut file_buffer = Vec::new();
+    file.read_to_end(&mut file_buffer).map_err(|_| "Couldn't read the program file")?;
+
+    Ok(file_buffer)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::fs::FsError;
+    use crate::syscall::Syscall;
+
+    // Encode an instruction word. `value` is the 4 bit immediate in Immediate mode
+    fn ins(instruction: Instructions, mode: u32, d: u32, a: u32, value: u32) -> u32{
+        ((instruction as u32) << 24) | (mode << 22) | (d << 12) | (a << 8) | (value << 4)
+    }
+
+    fn load(text: Vec<u32>) -> VirtualMachine{
+        let mut virtual_machine = VirtualMachine::new();
+        virtual_machine.load_executable(&Executable{text, ..Default::default()}).unwrap();
+        virtual_machine
+    }
+
+    // Position independent code, moved to `base`
+    fn load_at(text: Vec<u32>, base: u32) -> VirtualMachine{
+        let mut virtual_machine = VirtualMachine::new();
+        virtual_machine.load_executable_at(&Executable{text, relocations: Some(Vec::new()), ..Default::default()}, base).unwrap();
+        virtual_machine
+    }
+
+    // A relative jump, with a 4 bit offset unless it's extended
+    fn relative(instruction: Instructions, offset: i32) -> Vec<u32>{
+        let raw = ins(instruction, 1, 0, 0, 0) | 0x00100000;
+        if (-8..8).contains(&offset){
+            vec![raw | ((offset as u32 & 0xF) << 4)]
+        }else{
+            vec![raw | 0x1, offset as u32]
+        }
+    }
+
+    #[test]
+    fn relative_jumps_go_forward_and_back(){
+        let program = [
+            relative(Instructions::JMP, 4),   // 0: to 4
+            relative(Instructions::JMP, 8),   // 4: to 12
+            vec![ins(Instructions::HLT, 1, 0, 0, 5)],
+            relative(Instructions::JMP, -4),  // 12: back to 8
+        ].concat();
+        assert_eq!(program.len(), 5);
+
+        for base in [0, 0x100]{
+            assert_eq!(load_at(program.clone(), base).run(), Ok(5), "base 0x{:X}", base);
+        }
+    }
+
+    #[test]
+    fn calls_return_to_their_base(){
+        let program = [
+            relative(Instructions::CALL, 12), // 0: to 12
+            vec![
+                ins(Instructions::HLT, 0, 0, 0, 0) | HLT_STATUS_REGISTER, // 4: HLT R0
+                ins(Instructions::HLT, 1, 0, 0, 9),
+                ins(Instructions::SET, 1, 0, 0, 6), // 12
+                ins(Instructions::RET, 0, 0, 0, 0),
+            ],
+        ].concat();
+
+        let mut virtual_machine = load_at(program, 0x200);
+        assert_eq!(virtual_machine.run(), Ok(6));
+        assert_eq!(virtual_machine.registers.get_pc(), 0x204 / 4);
+    }
+
+    #[test]
+    fn guest_buffers_outside_of_memory_are_bus_errors(){
+        let mut virtual_machine = load(vec![0]);
+        let end = virtual_machine.memory.size() as u32;
+
+        assert_eq!(virtual_machine.write_guest_bytes(end - 0x10, &[0; 0x100]), Err(Fault::BusError{address: end - 0x10}));
+        assert_eq!(virtual_machine.read_guest_bytes(end - 0x10, 0x100), Err(Fault::BusError{address: end - 0x10}));
+        assert_eq!(virtual_machine.read_guest_bytes(0, usize::MAX), Err(Fault::BusError{address: 0}));
+        assert_eq!(virtual_machine.read_guest_bytes(end - 0x10, 0x10).map(|x| x.len()), Ok(0x10));
+    }
+
+    #[test]
+    fn invalid_control_registers_raise_invalid_opcode(){
+        let program = vec![
+            ins(Instructions::MTCR, 0, 9, 1, 0), // CR9 doesn't exist
+            ins(Instructions::HLT, 0, 0, 0, 0),
+            ins(Instructions::HLT, 1, 0, 0, 7), // The handler, at code address 8
+        ];
+
+        let mut virtual_machine = load(program.clone());
+        assert_eq!(virtual_machine.run(), Err(Fault::InvalidOpcode{opcode: Instructions::MTCR as u32}));
+
+        // With a handler, the guest gets to deal with it
+        let mut virtual_machine = load(program);
+        virtual_machine.registers.set_control(ControlRegister::IVT, 0x4000);
+        virtual_machine.memory.write::<u32>(0x4000 + VECTOR_INVALID_OPCODE as usize * 4, 8);
+        assert_eq!(virtual_machine.run(), Ok(7));
+        assert_eq!(virtual_machine.registers.get_control(ControlRegister::EPC), 0);
+
+        let mut virtual_machine = load(vec![ins(Instructions::MFCR, 0, 1, 12, 0)]);
+        assert_eq!(virtual_machine.run(), Err(Fault::InvalidOpcode{opcode: Instructions::MFCR as u32}));
+    }
+
+    #[test]
+    fn interrupt_lines_enter_their_handler_and_iret_returns(){
+        let program = vec![
+            ins(Instructions::EI, 0, 0, 0, 0),
+            ins(Instructions::HLT, 0, 1, 0, 0) | HLT_STATUS_REGISTER,
+            ins(Instructions::SET, 1, 1, 0, 5), // The handler, at code address 8
+            ins(Instructions::IRET, 0, 0, 0, 0),
+        ];
+
+        let mut virtual_machine = load(program.clone());
+        virtual_machine.registers.set_control(ControlRegister::IVT, 0x4000);
+        virtual_machine.memory.write::<u32>(0x4000 + (VECTOR_IRQ_BASE + 3) as usize * 4, 8);
+        virtual_machine.assert_interrupt(3);
+        let sp = virtual_machine.registers.get_sp();
+        assert_eq!(virtual_machine.run(), Ok(5));
+        assert_eq!(virtual_machine.pending_interrupts, 0);
+        assert_eq!(virtual_machine.registers.get_sp(), sp);
+        assert_ne!(virtual_machine.registers.get_interrupt_flag() & INTERRUPT_ENABLE, 0);
+
+        // An IVT of 0 is no table at all, whatever is at address 0
+        let mut virtual_machine = load(program);
+        virtual_machine.memory.write::<u32>((VECTOR_IRQ_BASE + 3) as usize * 4, 8);
+        virtual_machine.assert_interrupt(3);
+        assert_eq!(virtual_machine.run(), Ok(0));
+        assert_eq!(virtual_machine.pending_interrupts, 1 << 3);
+    }
+
+    #[test]
+    fn timer_interrupts_enter_their_handler(){
+        use crate::devices::timer::{TIMER_CONTROL, TIMER_CONTROL_ENABLE, TIMER_CONTROL_IRQ, TIMER_RELOAD};
+
+        let program = vec![
+            ins(Instructions::SET, 1, 1, 0, 3),
+            ins(Instructions::SD, 1, 1, 0, 0) | 0x1, TIMER_BASE + TIMER_RELOAD,
+            ins(Instructions::SET, 1, 1, 0, TIMER_CONTROL_ENABLE | TIMER_CONTROL_IRQ),
+            ins(Instructions::SD, 1, 1, 0, 0) | 0x1, TIMER_BASE + TIMER_CONTROL,
+            ins(Instructions::EI, 0, 0, 0, 0),
+            ins(Instructions::JMP, 1, 0, 0, 0) | 0x1, 20, // Wait for the timer
+            ins(Instructions::HLT, 1, 0, 0, 9), // The handler, at code address 24 - extension words don't count
+        ];
+
+        let mut virtual_machine = load(program);
+        virtual_machine.registers.set_control(ControlRegister::IVT, 0x4000);
+        virtual_machine.memory.write::<u32>(0x4000 + (VECTOR_IRQ_BASE + IRQ_TIMER) as usize * 4, 24);
+        let sp = virtual_machine.registers.get_sp();
+        assert_eq!(virtual_machine.run(), Ok(9));
+
+        // The interrupted JMP is where IRET would go back to
+        assert_eq!(virtual_machine.registers.get_sp(), sp - 8);
+        assert_eq!(virtual_machine.memory.read::<u32>(sp - 4), 20);
+    }
+
+    #[test]
+    fn leaving_the_program_is_a_bus_error(){
+        let mut virtual_machine = load(vec![ins(Instructions::SET, 1, 0, 0, 1)]); // No HLT
+        assert_eq!(virtual_machine.run(), Err(Fault::BusError{address: 4}));
+
+        // With a handler, the guest gets to deal with it
+        let mut virtual_machine = load(vec![ins(Instructions::JMP, 1, 0, 0, 0) | 0x1, 0x1000, ins(Instructions::HLT, 1, 0, 0, 7)]);
+        virtual_machine.registers.set_control(ControlRegister::IVT, 0x4000);
+        virtual_machine.memory.write::<u32>(0x4000 + VECTOR_BUS_ERROR as usize * 4, 4);
+        assert_eq!(virtual_machine.run(), Ok(7));
+        assert_eq!(virtual_machine.registers.get_control(ControlRegister::FAR), 0x1000);
+        assert_eq!(virtual_machine.registers.get_control(ControlRegister::EPC), 0x1000);
+    }
+
+    #[test]
+    fn addresses_and_arithmetic_wrap(){
+        let mut virtual_machine = load(vec![
+            ins(Instructions::ADD, 3, 1, 1, 2) | 8, // ADD R1, R1, [R2 + 8]
+            ins(Instructions::SUB, 0, 3, 3, 1),     // SUB R3, R3, R1
+            ins(Instructions::HLT, 1, 0, 0, 0),
+        ]);
+        virtual_machine.memory.write::<u32>(4, 2);
+        virtual_machine.registers.set_register(1, u32::MAX);
+        virtual_machine.registers.set_register(2, 0xFFFFFFFC);
+
+        assert_eq!(virtual_machine.run(), Ok(0));
+        assert_eq!(virtual_machine.registers.get_register(1), 1);
+        assert_eq!(virtual_machine.registers.get_register(3), u32::MAX);
+    }
+
+    #[test]
+    fn stores_and_loads_round_trip(){
+        let widths = [
+            // Store, load, value stored, value loaded
+            (Instructions::SD, Instructions::LD, 0xF2345678, 0xF2345678),
+            (Instructions::SD16, Instructions::LD16, 0xF2345678, 0x5678),
+            (Instructions::SD8, Instructions::LD8, 0xF2345678, 0x78),
+            (Instructions::SD16, Instructions::LD16S, 0x1234D678, 0xFFFFD678),
+            (Instructions::SD8, Instructions::LD8S, 0x123456F8, 0xFFFFFFF8),
+        ];
+
+        for (store, load_instruction, value, expected) in widths{
+            // Register mode (address in R2), then BaseOffset mode (R2 + 8)
+            let mut virtual_machine = load(vec![
+                ins(store, 0, 1, 2, 0),
+                ins(load_instruction, 0, 3, 2, 0),
+                ins(store, 3, 1, 2, 0) | 8,
+                ins(load_instruction, 3, 4, 2, 0) | 8,
+                ins(Instructions::HLT, 0, 0, 0, 0),
+            ]);
+            virtual_machine.registers.set_register(1, value);
+            virtual_machine.registers.set_register(2, 0x3000);
+            virtual_machine.run().unwrap();
+
+            assert_eq!(virtual_machine.registers.get_register(3), expected, "{:?}/{:?}", store, load_instruction);
+            assert_eq!(virtual_machine.registers.get_register(4), expected, "{:?}/{:?} with an offset", store, load_instruction);
+        }
+
+        // Immediate mode addresses are absolute
+        let mut virtual_machine = load(vec![ins(Instructions::SD, 1, 1, 0, 8), ins(Instructions::LD, 1, 2, 0, 8), ins(Instructions::HLT, 0, 0, 0, 0)]);
+        virtual_machine.registers.set_register(1, 0xCAFE);
+        virtual_machine.run().unwrap();
+        assert_eq!(virtual_machine.registers.get_register(2), 0xCAFE);
+        assert_eq!(virtual_machine.memory.read::<u32>(8), 0xCAFE);
+    }
+
+    #[test]
+    fn arguments_and_environment_are_on_the_stack(){
+        let mut virtual_machine = load(vec![0]);
+        let arguments = [String::from("prog"), String::from("a b")];
+        virtual_machine.set_arguments(&arguments, &[String::from("X=1")]).unwrap();
+
+        // The strings take 13 bytes, padded to 16. Below them are envp, then argv, each ending in 0
+        let strings = STACK_TOP - 16;
+        let envp = strings - 2 * 4;
+        let argv = envp - 3 * 4;
+        assert_eq!(virtual_machine.registers.get_register(1), 2);
+        assert_eq!(virtual_machine.registers.get_register(2), argv);
+        assert_eq!(virtual_machine.registers.get_register(3), envp);
+        assert_eq!(virtual_machine.registers.get_sp(), argv as usize);
+
+        let pointers: Vec<u32> = (0..5).map(|i| virtual_machine.memory.read::<u32>(argv as usize + i * 4)).collect();
+        assert_eq!(pointers, vec![strings, strings + 5, 0, strings + 9, 0]);
+
+        let mut bytes = [0u8; 16];
+        virtual_machine.memory.read_bytes(strings as usize, &mut bytes);
+        assert_eq!(&bytes, b"prog\0a b\0X=1\0\0\0\0");
+    }
+
+    #[test]
+    fn arguments_that_dont_fit_are_refused(){
+        let mut virtual_machine = load(vec![0]);
+        let sp = virtual_machine.registers.get_sp();
+
+        // With its NUL, argv[0] and the two 0 pointers, it's just too big
+        let argument = "x".repeat(ARGUMENTS_SIZE as usize - 8);
+        assert_eq!(virtual_machine.set_arguments(&[argument], &[]), Err("The arguments and environment don't fit on the stack"));
+        assert_eq!(virtual_machine.registers.get_sp(), sp);
+
+        // But one 12 bytes shorter fills the space exactly
+        let argument = "x".repeat(ARGUMENTS_SIZE as usize - 12 - 1);
+        assert_eq!(virtual_machine.set_arguments(&[argument], &[]), Ok(()));
+        assert_eq!(virtual_machine.registers.get_sp(), (STACK_TOP - ARGUMENTS_SIZE) as usize);
+    }
+
+    #[test]
+    fn seed_rng_restarts_the_sequence(){
+        let mut virtual_machine = load(vec![0]);
+        let sequence = |virtual_machine: &mut VirtualMachine| -> Vec<u32>{
+            (0..4).map(|_| virtual_machine.load(RNG_BASE, 4).unwrap()).collect()
+        };
+
+        virtual_machine.seed_rng(42);
+        let first = sequence(&mut virtual_machine);
+        assert_ne!(sequence(&mut virtual_machine), first);
+
+        virtual_machine.seed_rng(42);
+        assert_eq!(virtual_machine.get_rng_seed(), 42);
+        assert_eq!(sequence(&mut virtual_machine), first);
+
+        virtual_machine.seed_rng(43);
+        assert_ne!(sequence(&mut virtual_machine), first);
+    }
+
+    #[test]
+    fn host_functions_are_called_by_id(){
+        let program = vec![
+            ins(Instructions::SET, 1, 1, 0, 3),
+            ins(Instructions::HCALL, 1, 0, 0, 0) | 0x1, 0x10,
+            ins(Instructions::HCALL, 0, 2, 0, 0), // The id in R2
+            ins(Instructions::HLT, 0, 1, 0, 0) | HLT_STATUS_REGISTER,
+        ];
+
+        let mut virtual_machine = load(program.clone());
+        virtual_machine.registers.set_register(2, 0x11);
+        virtual_machine.register_host_fn(0x10, |context| {
+            let value = context.registers.get_register(1);
+            context.registers.set_register(1, value * 2);
+            context.memory.write::<u32>(0x3000, 0xBEEF);
+            Ok(())
+        });
+        virtual_machine.register_host_fn(0x11, |context| {
+            let value = context.registers.get_register(1);
+            context.registers.set_register(1, value + 1);
+            Ok(())
+        });
+        assert_eq!(virtual_machine.run(), Ok(7));
+        assert_eq!(virtual_machine.memory.read::<u32>(0x3000), 0xBEEF);
+
+        // Ids that aren't registered stop the VM
+        let mut virtual_machine = load(program.clone());
+        virtual_machine.registers.set_register(2, 0x11);
+        virtual_machine.register_host_fn(0x10, |_| Ok(()));
+        assert_eq!(virtual_machine.run(), Err(Fault::UnknownHostFunction{id: 0x11}));
+
+        let mut virtual_machine = load(program);
+        virtual_machine.register_host_fn(0x10, |_| Ok(()));
+        virtual_machine.unregister_host_fn(0x10);
+        assert_eq!(virtual_machine.run(), Err(Fault::UnknownHostFunction{id: 0x10}));
+    }
+
+    #[test]
+    fn host_function_errors_carry_the_pc(){
+        let mut virtual_machine = load(vec![
+            ins(Instructions::SET, 1, 1, 0, 3),
+            ins(Instructions::HCALL, 1, 0, 0, 5),
+            ins(Instructions::HLT, 0, 0, 0, 0),
+        ]);
+        virtual_machine.register_host_fn(5, |_| Err("Out of cheese".into()));
+
+        assert_eq!(virtual_machine.run(), Err(Fault::HostFunction{id: 5, pc: 4, message: String::from("Out of cheese")}));
+    }
+
+    #[test]
+    fn heap_grows_and_shrinks_between_its_limits(){
+        let mut virtual_machine = load(vec![0]);
+        assert_eq!(virtual_machine.get_heap_break(), HEAP_START);
+
+        assert_eq!(virtual_machine.sbrk(0x100), Ok(HEAP_START));
+        assert_eq!(virtual_machine.sbrk(-0x80), Ok(HEAP_START + 0x100));
+        assert_eq!(virtual_machine.get_heap_break(), HEAP_START + 0x80);
+        assert_eq!(virtual_machine.sbrk(-0x100), Err(Fault::HeapOverflow{requested: HEAP_START - 0x80}));
+
+        assert_eq!(virtual_machine.brk(STACK_LIMIT), Ok(STACK_LIMIT));
+        assert_eq!(virtual_machine.sbrk(4), Err(Fault::HeapOverflow{requested: STACK_LIMIT + 4}));
+        assert_eq!(virtual_machine.brk(STACK_LIMIT + 4), Err(Fault::HeapOverflow{requested: STACK_LIMIT + 4}));
+        assert_eq!(virtual_machine.get_heap_break(), STACK_LIMIT);
+        assert_eq!(virtual_machine.brk(HEAP_START), Ok(HEAP_START));
+    }
+
+    #[test]
+    fn heap_syscalls(){
+        // SET R0, number; SET R1, argument; SYS; HLT R0
+        let run = |number: Syscall, argument: u32, heap_break: u32| -> Result<u32, Fault>{
+            let mut virtual_machine = load(vec![
+                ins(Instructions::SET, 1, 0, 0, number as u32),
+                ins(Instructions::SET, 1, 1, 0, 0) | 0x1, argument,
+                ins(Instructions::SYS, 0, 0, 0, 0),
+                ins(Instructions::HLT, 0, 0, 0, 0) | HLT_STATUS_REGISTER,
+            ]);
+            virtual_machine.brk(heap_break).unwrap();
+            virtual_machine.run()
         };
 
-        return false;
+        // A break of 0 only asks where it is
+        assert_eq!(run(Syscall::BRK, 0, HEAP_START + 0x40), Ok(HEAP_START + 0x40));
+        assert_eq!(run(Syscall::BRK, HEAP_START + 0x80, HEAP_START), Ok(HEAP_START + 0x80));
+        assert_eq!(run(Syscall::SBRK, 0x10, HEAP_START + 0x40), Ok(HEAP_START + 0x40));
+        assert_eq!(run(Syscall::SBRK, -0x40i32 as u32, HEAP_START + 0x40), Ok(HEAP_START + 0x40));
+
+        // Running into the stack stops the VM
+        assert_eq!(run(Syscall::SBRK, 0x10, STACK_LIMIT - 8), Err(Fault::HeapOverflow{requested: STACK_LIMIT + 8}));
+        assert_eq!(run(Syscall::BRK, STACK_TOP, HEAP_START), Err(Fault::HeapOverflow{requested: STACK_TOP}));
+    }
+
+    #[test]
+    fn forked_vms_have_their_own_registers_and_memory(){
+        let mut parent = load(vec![ins(Instructions::HLT, 0, 1, 0, 0) | HLT_STATUS_REGISTER]);
+        parent.registers.set_register(1, 5);
+        parent.memory.write::<u32>(0x3000, 1);
+
+        let mut child = parent.fork();
+        child.registers.set_register(1, 6);
+        child.memory.write::<u32>(0x3000, 2);
+        parent.memory.write::<u32>(0x3004, 3);
+
+        assert_eq!(parent.memory.read::<u32>(0x3000), 1);
+        assert_eq!(child.memory.read::<u32>(0x3000), 2);
+        assert_eq!(child.memory.read::<u32>(0x3004), 0x01010101);
+        assert_eq!(parent.run(), Ok(5));
+        assert_eq!(child.run(), Ok(6));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn forked_disks_leave_the_image_alone(){
+        use crate::devices::disk::*;
+        use crate::layout::DISK_BASE;
+
+        // Run a disk command on sector 0, with the buffer at `buffer`
+        fn transfer(virtual_machine: &mut VirtualMachine, buffer: u32, command: u32) -> u32{
+            virtual_machine.store(DISK_BASE + DISK_STATUS, 4, DISK_STATUS_DONE).unwrap();
+            virtual_machine.store(DISK_BASE + DISK_BUFFER, 4, buffer).unwrap();
+            virtual_machine.store(DISK_BASE + DISK_COMMAND, 4, command).unwrap();
+            virtual_machine.devices.tick(&mut virtual_machine.memory);
+            virtual_machine.load(DISK_BASE + DISK_STATUS, 4).unwrap()
+        }
+
+        let path = std::env::temp_dir().join(format!("dbv-vm-fork-{}.img", std::process::id()));
+        std::fs::write(&path, [0x11; SECTOR_SIZE]).unwrap();
+
+        let mut parent = load(vec![0]);
+        parent.devices.attach(DISK_BASE, Box::new(Disk::open(&path, DiskMode::ReadWrite).unwrap()));
+        let mut child = parent.fork();
+
+        // The child sees its own writes
+        child.memory.fill(0x3000, SECTOR_SIZE, 0xAB);
+        assert_eq!(transfer(&mut child, 0x3000, DISK_COMMAND_WRITE), DISK_STATUS_DONE);
+        assert_eq!(transfer(&mut child, 0x4000, DISK_COMMAND_READ), DISK_STATUS_DONE);
+        assert_eq!(child.memory.read::<u8>(0x4000), 0xAB);
+
+        // But the parent and the image don't
+        assert_eq!(transfer(&mut parent, 0x4000, DISK_COMMAND_READ), DISK_STATUS_DONE);
+        assert_eq!(parent.memory.read::<u8>(0x4000), 0x11);
+
+        let image = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(image.unwrap(), vec![0x11; SECTOR_SIZE]);
+    }
+
+    const PAGE_TABLE: u32 = 0x10000;
+
+    // Turn the MMU on with only `mappings` (page, entry) mapped, plus the page table and the stack
+    // mapped to themselves
+    fn map_pages(virtual_machine: &mut VirtualMachine, mappings: &[(u32, u32)]){
+        use crate::mmu::{PAGE_COUNT, PAGE_SHIFT, PTBR_ENABLE, PTE_PRESENT, PTE_WRITABLE};
+
+        let table = PAGE_TABLE as usize;
+        virtual_machine.memory.fill(table, (PAGE_COUNT * 4) as usize, 0);
+
+        let table_pages = (PAGE_TABLE >> PAGE_SHIFT)..((PAGE_TABLE + PAGE_COUNT * 4) >> PAGE_SHIFT);
+        let stack_page = (virtual_machine.registers.get_sp() as u32 - 4) >> PAGE_SHIFT;
+        for page in table_pages.chain([stack_page]){
+            virtual_machine.memory.write::<u32>(table + page as usize * 4, (page << PAGE_SHIFT) | PTE_PRESENT | PTE_WRITABLE);
+        }
+        for (page, entry) in mappings{
+            virtual_machine.memory.write::<u32>(table + *page as usize * 4, *entry);
+        }
+
+        virtual_machine.registers.set_control(ControlRegister::PTBR, PAGE_TABLE | PTBR_ENABLE);
+    }
+
+    #[test]
+    fn loads_and_stores_go_through_the_page_table(){
+        use crate::mmu::{PTE_PRESENT, PTE_WRITABLE};
+
+        let program = vec![
+            ins(Instructions::SET, 1, 1, 0, 7),
+            ins(Instructions::SD, 1, 1, 0, 0) | 0x1, 0x5004,
+            ins(Instructions::LD, 1, 2, 0, 0) | 0x1, 0x5004,
+            ins(Instructions::LD, 1, 3, 0, 0) | 0x1, 0x6000,
+            ins(Instructions::HLT, 0, 0, 0, 0),
+        ];
+        let mappings = [(5, 0x20000 | PTE_PRESENT | PTE_WRITABLE), (6, 0x30000 | PTE_PRESENT)];
+
+        let mut virtual_machine = load(program);
+        map_pages(&mut virtual_machine, &mappings);
+        virtual_machine.memory.write::<u32>(0x30000, 99);
+        assert_eq!(virtual_machine.run(), Ok(0));
+        assert_eq!(virtual_machine.registers.get_register(2), 7);
+        assert_eq!(virtual_machine.registers.get_register(3), 99);
+        assert_eq!(virtual_machine.memory.read::<u32>(0x20004), 7);
+        assert_eq!(virtual_machine.memory.read::<u32>(0x5004), 0x01010101);
+
+        // Unmapped pages, and stores to pages that aren't writable, are page faults
+        let mut virtual_machine = load(vec![ins(Instructions::LD, 1, 2, 0, 0) | 0x1, 0x7000]);
+        map_pages(&mut virtual_machine, &mappings);
+        assert_eq!(virtual_machine.run(), Err(Fault::PageFault{address: 0x7000, write: false}));
+        assert_eq!(virtual_machine.registers.get_control(ControlRegister::FAR), 0x7000);
+
+        let mut virtual_machine = load(vec![ins(Instructions::SD, 1, 1, 0, 0) | 0x1, 0x6000]);
+        map_pages(&mut virtual_machine, &mappings);
+        assert_eq!(virtual_machine.run(), Err(Fault::PageFault{address: 0x6000, write: true}));
+        assert_eq!(virtual_machine.memory.read::<u32>(0x30000), 0x01010101);
+    }
+
+    #[test]
+    fn page_faults_go_to_the_guest_handler(){
+        use crate::mmu::{PTE_PRESENT, PTE_WRITABLE};
+
+        let program = vec![
+            ins(Instructions::LD, 1, 2, 0, 0) | 0x1, 0x7008,
+            ins(Instructions::HLT, 0, 2, 0, 0) | HLT_STATUS_REGISTER,
+            // The handler, at code address 8, maps the page and restarts the load
+            ins(Instructions::SET, 1, 3, 0, 0) | 0x1, 0x20000 | PTE_PRESENT | PTE_WRITABLE,
+            ins(Instructions::SD, 1, 3, 0, 0) | 0x1, PAGE_TABLE + 7 * 4,
+            ins(Instructions::IRET, 0, 0, 0, 0),
+        ];
+
+        let mut virtual_machine = load(program);
+        map_pages(&mut virtual_machine, &[]);
+        virtual_machine.registers.set_control(ControlRegister::IVT, 0x4000);
+        virtual_machine.memory.write::<u32>(0x4000 + VECTOR_PAGE_FAULT as usize * 4, 8);
+        virtual_machine.memory.write::<u32>(0x20008, 42);
+        assert_eq!(virtual_machine.run(), Ok(42));
+        assert_eq!(virtual_machine.registers.get_control(ControlRegister::FAR), 0x7008);
+        assert_eq!(virtual_machine.registers.get_control(ControlRegister::EPC), 0);
+    }
+
+    #[test]
+    fn accesses_can_cross_into_a_page_elsewhere(){
+        use crate::mmu::{PTE_PRESENT, PTE_WRITABLE};
+
+        let program = vec![
+            ins(Instructions::SD, 1, 1, 0, 0) | 0x1, 0x5FFE,
+            ins(Instructions::LD, 1, 2, 0, 0) | 0x1, 0x5FFE,
+            ins(Instructions::HLT, 0, 0, 0, 0),
+        ];
+
+        let mut virtual_machine = load(program);
+        map_pages(&mut virtual_machine, &[(5, 0x30000 | PTE_PRESENT | PTE_WRITABLE), (6, 0x20000 | PTE_PRESENT | PTE_WRITABLE)]);
+        virtual_machine.registers.set_register(1, 0x12345678);
+        assert_eq!(virtual_machine.run(), Ok(0));
+        assert_eq!(virtual_machine.registers.get_register(2), 0x12345678);
+        assert_eq!(virtual_machine.memory.read::<u16>(0x30FFE), 0x5678);
+        assert_eq!(virtual_machine.memory.read::<u16>(0x20000), 0x1234);
+
+        // Both pages have to be mapped
+        let mut virtual_machine = load(vec![ins(Instructions::LD, 1, 2, 0, 0) | 0x1, 0x5FFE]);
+        map_pages(&mut virtual_machine, &[(5, 0x30000 | PTE_PRESENT)]);
+        assert_eq!(virtual_machine.run(), Err(Fault::PageFault{address: 0x6000, write: false}));
+    }
+
+    #[test]
+    fn changed_page_table_entries_need_tlbf(){
+        use crate::mmu::PTE_PRESENT;
+
+        let program = vec![
+            ins(Instructions::LD, 1, 2, 0, 0) | 0x1, 0x5000,
+            ins(Instructions::SET, 1, 3, 0, 0) | 0x1, 0x30000 | PTE_PRESENT,
+            ins(Instructions::SD, 1, 3, 0, 0) | 0x1, PAGE_TABLE + 5 * 4,
+            ins(Instructions::LD, 1, 4, 0, 0) | 0x1, 0x5000, // Still the old frame
+            ins(Instructions::TLBF, 0, 0, 0, 0),
+            ins(Instructions::LD, 1, 5, 0, 0) | 0x1, 0x5000,
+            ins(Instructions::HLT, 0, 0, 0, 0),
+        ];
+
+        let mut virtual_machine = load(program);
+        map_pages(&mut virtual_machine, &[(5, 0x20000 | PTE_PRESENT)]);
+        virtual_machine.memory.write::<u32>(0x20000, 1);
+        virtual_machine.memory.write::<u32>(0x30000, 2);
+        assert_eq!(virtual_machine.run(), Ok(0));
+        assert_eq!(virtual_machine.registers.get_register(2), 1);
+        assert_eq!(virtual_machine.registers.get_register(4), 1);
+        assert_eq!(virtual_machine.registers.get_register(5), 2);
+    }
+
+    #[test]
+    fn alignment_policies(){
+        // SD R1, [R2]; LD R3, [R2]; HLT
+        let program = vec![ins(Instructions::SD, 0, 1, 2, 0), ins(Instructions::LD, 0, 3, 2, 0), ins(Instructions::HLT, 0, 0, 0, 0)];
+        let run = |policy: AlignmentPolicy, address: u32| -> (Result<u32, Fault>, VirtualMachine){
+            let mut virtual_machine = load(program.clone());
+            virtual_machine.set_alignment_policy(policy);
+            virtual_machine.registers.set_register(1, 0x12345678);
+            virtual_machine.registers.set_register(2, address);
+            (virtual_machine.run(), virtual_machine)
+        };
+
+        let (result, virtual_machine) = run(AlignmentPolicy::Fault, 0x3001);
+        assert_eq!(result, Err(Fault::Misaligned{address: 0x3001, width: 4}));
+        assert_eq!(virtual_machine.registers.get_control(ControlRegister::FAR), 0x3001);
+
+        // Emulated accesses work across a page boundary, and are counted
+        let (result, virtual_machine) = run(AlignmentPolicy::Emulate, 0x3FFE);
+        assert_eq!(result, Ok(0));
+        assert_eq!(virtual_machine.registers.get_register(3), 0x12345678);
+        assert_eq!(virtual_machine.memory.read::<u16>(0x4000), 0x1234);
+        assert_eq!(virtual_machine.get_misaligned_accesses(), 2);
+
+        let (result, virtual_machine) = run(AlignmentPolicy::Allow, 0x3001);
+        assert_eq!(result, Ok(0));
+        assert_eq!(virtual_machine.registers.get_register(3), 0x12345678);
+        assert_eq!(virtual_machine.get_misaligned_accesses(), 0);
+
+        // Aligned accesses are never a problem
+        assert_eq!(run(AlignmentPolicy::Fault, 0x3004).0, Ok(0));
+    }
+
+    #[test]
+    fn dumps_name_variables(){
+        let mut virtual_machine = load(vec![0]);
+        virtual_machine.memory.write::<u32>(0x2000, 0x2A);
+        assert_eq!(virtual_machine.data_dump(), vec!["0x00002000: 0x0000002A", "0x00002100: 0x01010101"]);
+
+        virtual_machine.set_debug_info(DebugInfo::parse("data 0x2000 counter\ndata 0x3000 total").unwrap());
+        assert_eq!(virtual_machine.data_dump(), vec!["0x00002000 (counter): 0x0000002A", "0x00003000 (total): 0x01010101"]);
+        assert_eq!(virtual_machine.symbolize_data(0x2002), "0x00002002 (counter+0x2)");
+    }
+
+    #[test]
+    fn read_longer_than_memory_is_refused(){
+        let mut virtual_machine = load(vec![ins(Instructions::SYS, 0, 0, 0, 0), ins(Instructions::HLT, 0, 0, 0, 0) | HLT_STATUS_REGISTER]);
+        virtual_machine.registers.set_register(0, Syscall::READ as u32);
+        virtual_machine.registers.set_register(1, 0);
+        virtual_machine.registers.set_register(3, u32::MAX);
+
+        assert_eq!(virtual_machine.run(), Ok(FsError::InvalidArgument.code()));
+    }
+}
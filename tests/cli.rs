@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// A scratch directory for one test's files
+fn scratch(name: &str) -> PathBuf{
+    let directory = std::env::temp_dir().join(format!("dbv-cli-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+// Write a headerless program and run it
+fn run_raw_output(directory: &PathBuf, words: &[u32], args: &[&str]) -> Output{
+    let path = directory.join("program.raw");
+    std::fs::write(&path, words.iter().flat_map(|x| x.to_be_bytes()).collect::<Vec<u8>>()).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_dbv_rs_new"))
+        .arg("run").arg(&path).arg("--raw").args(args)
+        .current_dir(directory)
+        .output().unwrap()
+}
+
+// Write a headerless program and run it, returning the exit code
+fn run_raw(directory: &PathBuf, words: &[u32], args: &[&str]) -> i32{
+    run_raw_output(directory, words, args).status.code().unwrap()
+}
+
+#[test]
+fn run_exits_with_the_program_status(){
+    let directory = scratch("status");
+
+    assert_eq!(run_raw(&directory, &[0x00000000], &[]), 0); // HLT
+    assert_eq!(run_raw(&directory, &[0x00400070], &[]), 7); // HLT 7
+    assert_eq!(run_raw(&directory, &[0x03405001, 300, 0x00005001], &[]), 255); // SET R5, 300; HLT R5 - too big for an exit code
+    assert_eq!(run_raw(&directory, &[0xFE000000], &[]), 1); // Invalid opcode, with no handler
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn loaded_files_and_memory_dumps(){
+    let directory = scratch("load-dump");
+    std::fs::write(directory.join("input.bin"), [1, 2, 3, 4, 5]).unwrap();
+
+    // LD R1, 0x3000; SD R1, 0x3100; HLT
+    let copy = [0x11401001, 0x3000, 0x10401001, 0x3100, 0x00000000];
+    let args = ["--load", "input.bin@0x3000", "--dump-mem", "0x3100:4=copy.bin", "--dump-mem", "12288:5=input.out"];
+    assert_eq!(run_raw(&directory, &copy, &args), 0);
+    assert_eq!(std::fs::read(directory.join("copy.bin")).unwrap(), [1, 2, 3, 4]);
+    assert_eq!(std::fs::read(directory.join("input.out")).unwrap(), [1, 2, 3, 4, 5]);
+
+    // Dumps are still written after a fault
+    assert_eq!(run_raw(&directory, &[0xFE000000], &["--load", "input.bin@0x3000", "--dump-mem", "0x3000:2=fault.bin"]), 1);
+    assert_eq!(std::fs::read(directory.join("fault.bin")).unwrap(), [1, 2]);
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn loads_and_dumps_outside_of_memory_are_refused(){
+    let directory = scratch("load-dump-range");
+    std::fs::write(directory.join("input.bin"), [1, 2, 3, 4, 5]).unwrap();
+    let halt = [0x00000000];
+
+    // Memory ends at 0xFFFFFF
+    assert_eq!(run_raw(&directory, &halt, &["--load", "input.bin@0xFFFFFA"]), 0);
+    assert_eq!(run_raw(&directory, &halt, &["--load", "input.bin@0xFFFFFB"]), 1);
+    assert_eq!(run_raw(&directory, &halt, &["--load", "missing.bin@0x3000"]), 1);
+
+    assert_eq!(run_raw(&directory, &halt, &["--dump-mem", "0xFFFFFE:1=end.bin"]), 0);
+    assert!(directory.join("end.bin").exists());
+    assert_eq!(run_raw(&directory, &halt, &["--dump-mem", "0xFFFFFE:2=past.bin"]), 1);
+    assert!(!directory.join("past.bin").exists());
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn malformed_loads_and_dumps_are_usage_errors(){
+    let directory = scratch("load-dump-usage");
+    let halt = [0x00000000];
+
+    for args in [
+        ["--load", "input.bin"],
+        ["--load", "@0x3000"],
+        ["--load", "input.bin@x"],
+        ["--dump-mem", "0x3000=out.bin"],
+        ["--dump-mem", "0x3000:4"],
+        ["--dump-mem", "0x3000:4="],
+        ["--dump-mem", "0x3000:0xZ=out.bin"],
+    ]{
+        let output = run_raw_output(&directory, &halt, &args);
+        assert_eq!(output.status.code(), Some(1), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with("Usage:"), "{:?}", args);
+    }
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
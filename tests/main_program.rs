@@ -0,0 +1,23 @@
+use dbv_rs_new::signature::TrustedKeys;
+use dbv_rs_new::vm::VirtualMachine;
+
+// The sample program sums part of the Fibonacci sequence into R5, checks whether the sum is prime,
+// and halts with status 0
+#[test]
+fn main_dbv_runs_to_hlt(){
+    let mut virtual_machine = VirtualMachine::new();
+    virtual_machine.load_program(concat!(env!("CARGO_MANIFEST_DIR"), "/main.dbv")).unwrap();
+
+    assert_eq!(virtual_machine.run(), Ok(0));
+    assert_eq!(virtual_machine.registers.get_register(5), 1);
+    assert_eq!(virtual_machine.memory.read::<u32>(0x2100), 0); // 1 isn't prime
+}
+
+#[test]
+fn trusted_keys_apply_to_load_program(){
+    let mut virtual_machine = VirtualMachine::new();
+    virtual_machine.set_trusted_keys(TrustedKeys::new());
+
+    let result = virtual_machine.load_program(concat!(env!("CARGO_MANIFEST_DIR"), "/main.dbv"));
+    assert_eq!(result, Err(String::from("Image isn't signed")));
+}